@@ -0,0 +1,367 @@
+//! Checkpoint files, so an interrupted scan can be continued with `--resume`.
+//!
+//! A checkpoint holds the scan's command line, the targets with what discovery found, and
+//! for every host started the raw scan states, the ports already probed and the open ports
+//! found so far. It is rewritten periodically, each copy replacing the last atomically.
+
+use crate::discovery::{HostState, HostStatus};
+use crate::fingerprint::Fingerprint;
+use crate::os::OsDetection;
+use crate::rawscan::PortState;
+use crate::targets::Target;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Bumped whenever the file layout changes incompatibly.
+const FORMAT_VERSION: u32 = 1;
+/// How often a running scan rewrites its checkpoint.
+pub const SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+pub struct Checkpoint {
+    path: PathBuf,
+    saved: Mutex<Saved>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Saved {
+    version: u32,
+    /// Arguments the scan was started with, replayed by `--resume`.
+    args: Vec<String>,
+    /// Expanded targets with their discovery results, once discovery has finished.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    targets: Vec<SavedTarget>,
+    #[serde(default)]
+    hosts: BTreeMap<IpAddr, HostProgress>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedTarget {
+    ip: IpAddr,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hostname: Option<String>,
+    state: HostState,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    latency_us: Option<u64>,
+    reason: String,
+}
+
+/// How far the scan of one host got.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HostProgress {
+    /// Every port's state from the raw scan, once it has run.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub raw_states: Option<BTreeMap<u16, PortState>>,
+    /// Ports whose connect or probe has finished, as a port list like "1-1024,8080".
+    #[serde(
+        default,
+        with = "port_list",
+        skip_serializing_if = "BTreeSet::is_empty"
+    )]
+    pub done: BTreeSet<u16>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub open: Vec<(u16, Fingerprint)>,
+    /// Connects retried so far, and how many of them were answered.
+    #[serde(default)]
+    pub retried: usize,
+    #[serde(default)]
+    pub recovered: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub finished: Option<Finished>,
+}
+
+/// What is left to report once a host's ports are all done.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Finished {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub os_detection: Option<OsDetection>,
+    pub retried: usize,
+    pub recovered: usize,
+    /// `--host-timeout` gave up on the host.
+    #[serde(default)]
+    pub timed_out: bool,
+}
+
+impl Checkpoint {
+    /// A new, empty checkpoint for a scan started with `args`.
+    pub fn new(path: PathBuf, args: Vec<String>) -> Arc<Self> {
+        Arc::new(Checkpoint {
+            path,
+            saved: Mutex::new(Saved {
+                version: FORMAT_VERSION,
+                args,
+                targets: Vec::new(),
+                hosts: BTreeMap::new(),
+            }),
+        })
+    }
+
+    /// Reads a checkpoint written by an earlier run; saving writes back to the same file.
+    pub fn load(path: &Path) -> Result<Arc<Self>, String> {
+        let source = std::fs::read_to_string(path)
+            .map_err(|e| format!("{}: could not read checkpoint: {}", path.display(), e))?;
+        let saved: Saved = serde_json::from_str(&source)
+            .map_err(|e| format!("{}: not a PortDog checkpoint: {}", path.display(), e))?;
+        if saved.version != FORMAT_VERSION {
+            return Err(format!(
+                "{}: checkpoint format {} is not supported (expected {})",
+                path.display(),
+                saved.version,
+                FORMAT_VERSION
+            ));
+        }
+        Ok(Arc::new(Checkpoint {
+            path: path.to_path_buf(),
+            saved: Mutex::new(saved),
+        }))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The arguments the checkpointed scan was started with.
+    pub fn args(&self) -> Vec<String> {
+        self.saved.lock().unwrap().args.clone()
+    }
+
+    /// The targets and their discovery results, if discovery had finished.
+    pub fn targets(&self) -> Option<(Vec<Target>, Vec<HostStatus>)> {
+        let saved = self.saved.lock().unwrap();
+        if saved.targets.is_empty() {
+            return None;
+        }
+        Some(
+            saved
+                .targets
+                .iter()
+                .map(|t| {
+                    let target = Target {
+                        ip: t.ip,
+                        hostname: t.hostname.clone(),
+                    };
+                    let status = HostStatus::restore(
+                        t.state,
+                        t.latency_us.map(Duration::from_micros),
+                        &t.reason,
+                    );
+                    (target, status)
+                })
+                .unzip(),
+        )
+    }
+
+    pub fn record_targets(&self, targets: &[Target], statuses: &[HostStatus]) {
+        self.saved.lock().unwrap().targets = targets
+            .iter()
+            .zip(statuses)
+            .map(|(target, status)| SavedTarget {
+                ip: target.ip,
+                hostname: target.hostname.clone(),
+                state: status.state,
+                latency_us: status.latency.map(|l| l.as_micros() as u64),
+                reason: status.reason.to_string(),
+            })
+            .collect();
+    }
+
+    /// What was saved for `ip`; empty for a host not started yet.
+    pub fn host(&self, ip: IpAddr) -> HostProgress {
+        let saved = self.saved.lock().unwrap();
+        saved.hosts.get(&ip).cloned().unwrap_or_default()
+    }
+
+    pub fn record_raw_states(&self, ip: IpAddr, states: &BTreeMap<u16, PortState>) {
+        let mut saved = self.saved.lock().unwrap();
+        saved.hosts.entry(ip).or_default().raw_states = Some(states.clone());
+    }
+
+    /// Marks `port` done, keeping its fingerprint if it turned out open.
+    pub fn record_port(&self, ip: IpAddr, port: u16, open: Option<&Fingerprint>) {
+        let mut saved = self.saved.lock().unwrap();
+        let host = saved.hosts.entry(ip).or_default();
+        host.done.insert(port);
+        if let Some(fingerprint) = open {
+            host.open.push((port, fingerprint.clone()));
+        }
+    }
+
+    /// Updates the host's retry counts as of its latest done port.
+    pub fn record_retries(&self, ip: IpAddr, retried: usize, recovered: usize) {
+        let mut saved = self.saved.lock().unwrap();
+        let host = saved.hosts.entry(ip).or_default();
+        host.retried = retried;
+        host.recovered = recovered;
+    }
+
+    /// Marks the host finished. Its done ports are no longer needed and are dropped.
+    pub fn record_finished(&self, ip: IpAddr, finished: Finished) {
+        let mut saved = self.saved.lock().unwrap();
+        let host = saved.hosts.entry(ip).or_default();
+        host.done.clear();
+        host.finished = Some(finished);
+    }
+
+    /// Writes the checkpoint to a temporary file and renames it over the previous copy.
+    pub fn save(&self) -> Result<(), String> {
+        let contents = serde_json::to_vec(&*self.saved.lock().unwrap())
+            .map_err(|e| format!("could not encode checkpoint: {}", e))?;
+        let mut temporary = self.path.clone().into_os_string();
+        temporary.push(".tmp");
+        std::fs::write(&temporary, contents)
+            .and_then(|()| std::fs::rename(&temporary, &self.path))
+            .map_err(|e| format!("{}: could not save checkpoint: {}", self.path.display(), e))
+    }
+}
+
+/// Stores a port set as a compact list of ranges.
+mod port_list {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::collections::BTreeSet;
+
+    pub fn serialize<S: Serializer>(
+        ports: &BTreeSet<u16>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let mut ranges: Vec<String> = Vec::new();
+        let mut ports = ports.iter().copied().peekable();
+        while let Some(start) = ports.next() {
+            let mut end = start;
+            while ports.peek() == Some(&end.wrapping_add(1)) && end != u16::MAX {
+                end = ports.next().unwrap();
+            }
+            ranges.push(if start == end {
+                start.to_string()
+            } else {
+                format!("{}-{}", start, end)
+            });
+        }
+        serializer.serialize_str(&ranges.join(","))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<BTreeSet<u16>, D::Error> {
+        let list = String::deserialize(deserializer)?;
+        let mut ports = BTreeSet::new();
+        for range in list.split(',').filter(|r| !r.is_empty()) {
+            let (start, end) = range.split_once('-').unwrap_or((range, range));
+            let start: u16 = start.parse().map_err(serde::de::Error::custom)?;
+            let end: u16 = end.parse().map_err(serde::de::Error::custom)?;
+            ports.extend(start..=end);
+        }
+        Ok(ports)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("portdog-{}-{}.json", name, std::process::id()))
+    }
+
+    #[test]
+    fn port_lists_are_stored_as_ranges() {
+        #[derive(Serialize, Deserialize)]
+        struct Ports(#[serde(with = "port_list")] BTreeSet<u16>);
+
+        let ports = Ports([1, 2, 3, 5, 80, 81, 65534, 65535].into());
+        let json = serde_json::to_string(&ports).unwrap();
+        assert_eq!(json, r#""1-3,5,80-81,65534-65535""#);
+        let back: Ports = serde_json::from_str(&json).unwrap();
+        assert_eq!(back.0, ports.0);
+
+        let empty: Ports = serde_json::from_str(r#""""#).unwrap();
+        assert!(empty.0.is_empty());
+        assert!(serde_json::from_str::<Ports>(r#""1-x""#).is_err());
+    }
+
+    #[test]
+    fn progress_survives_a_save_and_load() {
+        let path = temp_path("checkpoint-round-trip");
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let args = vec![
+            "192.0.2.1".to_string(),
+            "-p".to_string(),
+            "1-100".to_string(),
+        ];
+        let checkpoint = Checkpoint::new(path.clone(), args.clone());
+        let targets = [Target {
+            ip,
+            hostname: Some("example.test".to_string()),
+        }];
+        let status = HostStatus::restore(HostState::Up, Some(Duration::from_micros(1500)), "reset");
+        checkpoint.record_targets(&targets, &[status]);
+        checkpoint.record_raw_states(ip, &BTreeMap::from([(22, PortState::Open)]));
+        let ssh = Fingerprint {
+            service_name: "ssh".to_string(),
+            banner: "OpenSSH_9.6".to_string(),
+            ..Default::default()
+        };
+        for port in 1..=30 {
+            checkpoint.record_port(ip, port, (port == 22).then_some(&ssh));
+        }
+        checkpoint.record_retries(ip, 4, 1);
+        checkpoint.save().unwrap();
+
+        let loaded = Checkpoint::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.args(), args);
+        let (targets, statuses) = loaded.targets().unwrap();
+        assert_eq!(targets[0].hostname.as_deref(), Some("example.test"));
+        assert_eq!(statuses[0].state, HostState::Up);
+        assert_eq!(statuses[0].latency, Some(Duration::from_micros(1500)));
+        assert_eq!(statuses[0].reason, "reset");
+
+        let host = loaded.host(ip);
+        assert_eq!(host.done, (1..=30).collect());
+        assert_eq!(host.open.len(), 1);
+        assert_eq!(
+            (host.open[0].0, host.open[0].1.banner.as_str()),
+            (22, "OpenSSH_9.6")
+        );
+        assert_eq!(host.raw_states.unwrap()[&22], PortState::Open);
+        assert_eq!((host.retried, host.recovered), (4, 1));
+        assert!(host.finished.is_none());
+        assert!(loaded.host("192.0.2.2".parse().unwrap()).done.is_empty());
+    }
+
+    #[test]
+    fn finishing_a_host_drops_its_done_ports() {
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let checkpoint = Checkpoint::new(temp_path("checkpoint-finished"), Vec::new());
+        checkpoint.record_port(ip, 80, None);
+        checkpoint.record_finished(
+            ip,
+            Finished {
+                retried: 2,
+                recovered: 2,
+                ..Default::default()
+            },
+        );
+        let host = checkpoint.host(ip);
+        assert!(host.done.is_empty());
+        assert_eq!(host.finished.unwrap().recovered, 2);
+    }
+
+    #[test]
+    fn refuses_other_formats() {
+        let path = temp_path("checkpoint-version");
+        std::fs::write(&path, r#"{"version": 99, "args": []}"#).unwrap();
+        let error = Checkpoint::load(&path).err().unwrap();
+        assert!(
+            error.ends_with("checkpoint format 99 is not supported (expected 1)"),
+            "{}",
+            error
+        );
+        std::fs::write(&path, "not json").unwrap();
+        let error = Checkpoint::load(&path).err().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(error.contains("not a PortDog checkpoint"), "{}", error);
+    }
+}
@@ -0,0 +1,211 @@
+//! Live congestion control for connect scans, in the spirit of nmap's congestion window.
+//!
+//! Every answered connect, accepted or refused, is an RTT sample: it updates a smoothed
+//! RTT and variance (RFC 6298) from which the connect timeout is derived, and grows the
+//! window of connects allowed in flight. A timeout on a host that has answered before
+//! counts as a drop and halves the window, at most once per window of probes.
+
+use crate::pacing::HostPacer;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+/// Timeouts derived from RTT samples never go below this.
+const MIN_TIMEOUT: Duration = Duration::from_millis(100);
+/// Nor above this.
+const MAX_TIMEOUT: Duration = Duration::from_secs(10);
+/// How far above the template's timeout drops and slow replies may push it.
+const TIMEOUT_CEILING_FACTOR: u32 = 4;
+/// The window never shrinks below the ceiling divided by this.
+const MIN_WINDOW_DIVISOR: f64 = 20.0;
+
+pub struct Controller {
+    state: Mutex<State>,
+    slot_freed: Notify,
+    pacer: Arc<HostPacer>,
+}
+
+struct State {
+    window: f64,
+    /// Below this the window grows by one per answer (slow start), above it by one per window.
+    ssthresh: f64,
+    min_window: f64,
+    max_window: f64,
+    in_flight: usize,
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    timeout: Duration,
+    min_timeout: Duration,
+    max_timeout: Duration,
+    /// Whether the host has answered at all; silence from a host that never did is not loss.
+    responsive: bool,
+    last_drop: Option<Instant>,
+    drops: usize,
+}
+
+/// A connect in flight. Report how it ended with `answered` or `timed_out`; dropping it
+/// without either frees the slot without telling the controller anything.
+pub struct Permit {
+    controller: Arc<Controller>,
+    sent: Instant,
+}
+
+impl Controller {
+    /// A controller allowing at most `max_in_flight` connects, starting from `timeout`.
+    /// `initial_rtt`, when host discovery measured one, seeds the RTT estimate. Every
+    /// connect also waits on `pacer`, which may override the window for `--min-rate`.
+    pub fn new(
+        max_in_flight: usize,
+        timeout: Duration,
+        initial_rtt: Option<Duration>,
+        pacer: Arc<HostPacer>,
+    ) -> Arc<Self> {
+        let max_window = max_in_flight.max(1) as f64;
+        let mut state = State {
+            window: max_window,
+            ssthresh: max_window,
+            min_window: (max_window / MIN_WINDOW_DIVISOR).max(1.0),
+            max_window,
+            in_flight: 0,
+            srtt: None,
+            rttvar: Duration::ZERO,
+            timeout,
+            min_timeout: MIN_TIMEOUT.min(timeout),
+            max_timeout: (timeout * TIMEOUT_CEILING_FACTOR)
+                .min(MAX_TIMEOUT)
+                .max(timeout),
+            responsive: false,
+            last_drop: None,
+            drops: 0,
+        };
+        if let Some(rtt) = initial_rtt {
+            state.sample(rtt);
+        }
+        Arc::new(Controller {
+            state: Mutex::new(state),
+            slot_freed: Notify::new(),
+            pacer,
+        })
+    }
+
+    /// Waits until the window has room for another connect and the pacer lets it go.
+    pub async fn acquire(self: &Arc<Self>) -> Permit {
+        let overdue_send = loop {
+            let freed = self.slot_freed.notified();
+            {
+                let mut state = self.state.lock().unwrap();
+                if (state.in_flight as f64) < state.window.floor() {
+                    state.in_flight += 1;
+                    break None;
+                }
+            }
+            let Some(due) = self.pacer.min_rate_deadline() else {
+                freed.await;
+                continue;
+            };
+            tokio::select! {
+                _ = freed => {}
+                _ = tokio::time::sleep_until(due) => {
+                    // --min-rate outranks the window.
+                    if let Some(send_at) = self.pacer.claim_overdue() {
+                        self.state.lock().unwrap().in_flight += 1;
+                        break Some(send_at);
+                    }
+                }
+            }
+        };
+        match overdue_send {
+            Some(send_at) => tokio::time::sleep_until(send_at).await,
+            None => self.pacer.wait().await,
+        }
+        Permit {
+            controller: Arc::clone(self),
+            sent: Instant::now(),
+        }
+    }
+
+    /// The connect timeout to use right now.
+    pub fn timeout(&self) -> Duration {
+        self.state.lock().unwrap().timeout
+    }
+
+    /// Connects currently allowed in flight.
+    pub fn window(&self) -> usize {
+        self.state.lock().unwrap().window as usize
+    }
+
+    /// Changes how many connects may ever be in flight, as the `+`/`-` keys do.
+    pub fn set_max_in_flight(&self, max_in_flight: usize) {
+        {
+            let mut state = self.state.lock().unwrap();
+            let max_window = max_in_flight.max(1) as f64;
+            // Growth is handed straight to the window; a cut only trims it.
+            state.window =
+                (state.window + (max_window - state.max_window).max(0.0)).min(max_window);
+            state.ssthresh = state.ssthresh.min(max_window);
+            state.min_window = (max_window / MIN_WINDOW_DIVISOR).max(1.0);
+            state.max_window = max_window;
+        }
+        self.slot_freed.notify_waiters();
+    }
+
+    /// How many times loss was detected and the window cut.
+    pub fn drops(&self) -> usize {
+        self.state.lock().unwrap().drops
+    }
+}
+
+impl State {
+    fn sample(&mut self, rtt: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                self.rttvar = self.rttvar * 3 / 4 + srtt.abs_diff(rtt) / 4;
+                self.srtt = Some(srtt * 7 / 8 + rtt / 8);
+            }
+        }
+        let srtt = self.srtt.unwrap_or(rtt);
+        self.timeout = (srtt + self.rttvar * 4).clamp(self.min_timeout, self.max_timeout);
+        self.responsive = true;
+    }
+}
+
+impl Permit {
+    /// The host accepted or refused the connection.
+    pub fn answered(self) {
+        let rtt = self.sent.elapsed();
+        let mut state = self.controller.state.lock().unwrap();
+        state.sample(rtt);
+        let growth = if state.window < state.ssthresh {
+            1.0
+        } else {
+            1.0 / state.window
+        };
+        state.window = (state.window + growth).min(state.max_window);
+    }
+
+    /// Nothing came back in time.
+    pub fn timed_out(self) {
+        let mut state = self.controller.state.lock().unwrap();
+        // Probes sent before the last cut were already accounted for by it.
+        if !state.responsive || state.last_drop.is_some_and(|cut| self.sent < cut) {
+            return;
+        }
+        state.drops += 1;
+        state.last_drop = Some(Instant::now());
+        state.ssthresh = (state.in_flight as f64 * 0.75).max(state.min_window);
+        state.window = (state.window / 2.0).max(state.min_window);
+        state.timeout = (state.timeout * 2).min(state.max_timeout);
+        self.controller.pacer.slow_down();
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.controller.state.lock().unwrap().in_flight -= 1;
+        self.controller.slot_freed.notify_one();
+    }
+}
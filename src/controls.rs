@@ -0,0 +1,278 @@
+//! Keyboard controls while a scan runs: `p` pauses and resumes, `+`/`-` change the
+//! concurrency, `v` toggles per-port events, `s` prints what was found so far and `q` stops
+//! the scan gracefully. Every running host watches the shared state and shows it on its
+//! progress line.
+
+use crate::shutdown::Shutdown;
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+/// How often the key reader checks whether the scan has ended.
+const KEY_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+pub struct Controls {
+    state: watch::Sender<State>,
+    /// `+` never raises the concurrency above this.
+    max_concurrency: usize,
+    shutdown: Arc<Shutdown>,
+    /// Open ports found so far, per host, for `s`.
+    findings: Mutex<BTreeMap<IpAddr, Vec<String>>>,
+    pauses: Mutex<PauseClock>,
+}
+
+/// Time spent paused, which doesn't count against `--host-timeout`.
+#[derive(Default)]
+struct PauseClock {
+    since: Option<Instant>,
+    total: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct State {
+    pub paused: bool,
+    /// Connects and UDP probes in flight across all hosts.
+    pub concurrency: usize,
+    pub verbose: bool,
+    pub stopping: bool,
+}
+
+/// A key that changes the scan, as read by `watch_keys`.
+enum Key {
+    Pause,
+    Faster,
+    Slower,
+    Verbose,
+    Findings,
+    Quit,
+}
+
+impl Controls {
+    pub fn new(concurrency: usize, max_concurrency: usize, shutdown: Arc<Shutdown>) -> Arc<Self> {
+        Arc::new(Controls {
+            state: watch::Sender::new(State {
+                paused: false,
+                concurrency,
+                verbose: false,
+                stopping: false,
+            }),
+            max_concurrency: max_concurrency.max(concurrency),
+            shutdown,
+            findings: Mutex::new(BTreeMap::new()),
+            pauses: Mutex::default(),
+        })
+    }
+
+    pub fn state(&self) -> State {
+        *self.state.borrow()
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<State> {
+        self.state.subscribe()
+    }
+
+    pub fn max_concurrency(&self) -> usize {
+        self.max_concurrency
+    }
+
+    /// Waits while the scan is paused. Stopping ends the pause.
+    pub async fn wait_if_paused(&self) {
+        if !self.state().paused {
+            return;
+        }
+        let mut state = self.state.subscribe();
+        let _ = state.wait_for(|s| !s.paused || s.stopping).await;
+    }
+
+    /// How long the scan has been paused in total, the current pause included.
+    pub fn paused_time(&self) -> Duration {
+        let pauses = self.pauses.lock().unwrap();
+        pauses.total + pauses.since.map_or(Duration::ZERO, |since| since.elapsed())
+    }
+
+    /// Stops the scan gracefully, as Ctrl-C does.
+    pub fn stop(&self) {
+        self.shutdown.request();
+        self.state.send_modify(|s| s.stopping = true);
+    }
+
+    pub fn record_open(&self, ip: IpAddr, finding: String) {
+        self.findings
+            .lock()
+            .unwrap()
+            .entry(ip)
+            .or_default()
+            .push(finding);
+    }
+
+    /// One line per host with open ports found so far.
+    pub fn findings(&self) -> Vec<String> {
+        self.findings
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(ip, found)| format!("{}: {}", ip, found.join(", ")))
+            .collect()
+    }
+
+    fn apply(&self, key: Key) {
+        match key {
+            Key::Pause => self.state.send_modify(|s| {
+                s.paused = !s.paused;
+                let mut pauses = self.pauses.lock().unwrap();
+                match pauses.since.take() {
+                    Some(since) => pauses.total += since.elapsed(),
+                    None => pauses.since = Some(Instant::now()),
+                }
+            }),
+            Key::Faster => self.state.send_modify(|s| {
+                s.concurrency =
+                    (s.concurrency + (s.concurrency / 4).max(1)).min(self.max_concurrency)
+            }),
+            Key::Slower => self.state.send_modify(|s| {
+                s.concurrency = s
+                    .concurrency
+                    .saturating_sub((s.concurrency / 5).max(1))
+                    .max(1)
+            }),
+            Key::Verbose => self.state.send_modify(|s| s.verbose = !s.verbose),
+            Key::Quit => self.stop(),
+            Key::Findings => {}
+        }
+    }
+}
+
+/// The state as shown at the end of a progress line; empty while nothing is unusual.
+pub fn describe(state: State, initial_concurrency: usize) -> String {
+    let mut parts = Vec::new();
+    if state.stopping {
+        parts.push("stopping".to_string());
+    } else if state.paused {
+        parts.push("paused (p to resume)".to_string());
+    }
+    if state.concurrency != initial_concurrency {
+        parts.push(format!("concurrency {}", state.concurrency));
+    }
+    if state.verbose {
+        parts.push("verbose".to_string());
+    }
+    if parts.is_empty() {
+        String::new()
+    } else {
+        format!("| {}", parts.join(", "))
+    }
+}
+
+/// Reads keys on a background thread until the returned guard is dropped, calling
+/// `show_findings` for `s`. Returns `None` when stdin is not a terminal.
+pub fn watch_keys(
+    controls: Arc<Controls>,
+    show_findings: impl Fn(Vec<String>) + Send + 'static,
+) -> Option<KeyWatcher> {
+    use std::io::IsTerminal;
+    if !std::io::stdin().is_terminal() {
+        return None;
+    }
+    if !enable_cbreak() {
+        return None;
+    }
+    let done = Arc::new(AtomicBool::new(false));
+    let thread_done = Arc::clone(&done);
+    std::thread::spawn(move || {
+        while !thread_done.load(Ordering::SeqCst) {
+            let Some(key) = next_key() else {
+                continue;
+            };
+            if let Key::Findings = key {
+                show_findings(controls.findings());
+            }
+            controls.apply(key);
+        }
+    });
+    Some(KeyWatcher { done })
+}
+
+fn next_key() -> Option<Key> {
+    use crossterm::event::{self, Event, KeyCode, KeyEventKind};
+    if !event::poll(KEY_POLL_INTERVAL).ok()? {
+        return None;
+    }
+    let Event::Key(key) = event::read().ok()? else {
+        return None;
+    };
+    if key.kind == KeyEventKind::Release {
+        return None;
+    }
+    match key.code {
+        KeyCode::Char('p' | 'P' | ' ') => Some(Key::Pause),
+        KeyCode::Char('+' | '=') => Some(Key::Faster),
+        KeyCode::Char('-' | '_') => Some(Key::Slower),
+        KeyCode::Char('v' | 'V') => Some(Key::Verbose),
+        KeyCode::Char('s' | 'S') => Some(Key::Findings),
+        KeyCode::Char('q' | 'Q') => Some(Key::Quit),
+        _ => None,
+    }
+}
+
+/// Stops reading keys and restores the terminal when dropped.
+pub struct KeyWatcher {
+    done: Arc<AtomicBool>,
+}
+
+impl Drop for KeyWatcher {
+    fn drop(&mut self) {
+        self.done.store(true, Ordering::SeqCst);
+        restore_terminal();
+    }
+}
+
+/// Terminal settings from before `enable_cbreak`, put back by `restore_terminal`.
+#[cfg(unix)]
+static ORIGINAL_MODE: Mutex<Option<libc::termios>> = Mutex::new(None);
+
+/// Keys arrive without Enter and are not echoed, while output and Ctrl-C work as usual,
+/// which crossterm's raw mode would not allow.
+#[cfg(unix)]
+fn enable_cbreak() -> bool {
+    // SAFETY: tcgetattr and tcsetattr only read and write the termios struct passed in.
+    unsafe {
+        let mut original: libc::termios = std::mem::zeroed();
+        if libc::tcgetattr(libc::STDIN_FILENO, &mut original) != 0 {
+            return false;
+        }
+        let mut cbreak = original;
+        cbreak.c_lflag &= !(libc::ICANON | libc::ECHO);
+        cbreak.c_cc[libc::VMIN] = 1;
+        cbreak.c_cc[libc::VTIME] = 0;
+        if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &cbreak) != 0 {
+            return false;
+        }
+        *ORIGINAL_MODE.lock().unwrap() = Some(original);
+        true
+    }
+}
+
+/// Undoes `enable_cbreak`; safe to call when it never ran. Call before `process::exit`.
+#[cfg(unix)]
+pub fn restore_terminal() {
+    if let Some(original) = ORIGINAL_MODE.lock().unwrap().take() {
+        // SAFETY: restores the settings read in `enable_cbreak`.
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &original);
+        }
+    }
+}
+
+/// On Windows, raw mode only changes console input, so output is unaffected.
+#[cfg(not(unix))]
+fn enable_cbreak() -> bool {
+    crossterm::terminal::enable_raw_mode().is_ok()
+}
+
+#[cfg(not(unix))]
+pub fn restore_terminal() {
+    let _ = crossterm::terminal::disable_raw_mode();
+}
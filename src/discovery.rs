@@ -0,0 +1,222 @@
+//! Host discovery: find out which targets are up before spending port timeouts on them.
+//!
+//! A host is up if any TCP ping port answers (with SYN/ACK or RST), if it replies to an
+//! ICMP echo (raw sockets permitting), or, for hosts on a local segment, if the kernel's
+//! ARP cache holds a resolved entry for it once the pings were sent. No ARP requests of
+//! our own are sent; the cache is only read.
+
+use crate::pacing::Pacer;
+use crate::raw::{self, RawSocket};
+use crate::source;
+use futures::FutureExt;
+use futures::stream::{self, FuturesUnordered, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::time::timeout;
+
+/// Hosts pinged at the same time.
+const DISCOVERY_PARALLELISM: usize = 64;
+/// Every reason a status can give.
+const REASONS: &[&str] = &[
+    "syn-ack",
+    "reset",
+    "echo-reply",
+    "arp-cache",
+    "user-set",
+    "no-response",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HostState {
+    Up,
+    Down,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct HostStatus {
+    pub state: HostState,
+    /// Round trip of the first ping answered; unknown for ARP and `-Pn`.
+    pub latency: Option<Duration>,
+    /// What showed the host to be up: "syn-ack", "reset", "echo-reply", "arp-cache"
+    /// or "user-set"; "no-response" for down hosts.
+    pub reason: &'static str,
+}
+
+impl HostStatus {
+    /// The status of a host discovery was told to skip.
+    pub fn assumed_up() -> Self {
+        HostStatus {
+            state: HostState::Up,
+            latency: None,
+            reason: "user-set",
+        }
+    }
+
+    /// A status read back from a checkpoint; unknown reasons become "user-set".
+    pub fn restore(state: HostState, latency: Option<Duration>, reason: &str) -> Self {
+        // Older checkpoints called the ARP cache lookup "arp-response".
+        let reason = if reason == "arp-response" {
+            "arp-cache"
+        } else {
+            reason
+        };
+        HostStatus {
+            state,
+            latency,
+            reason: REASONS
+                .iter()
+                .copied()
+                .find(|&known| known == reason)
+                .unwrap_or("user-set"),
+        }
+    }
+}
+
+/// Pings every target, returning their statuses in the same order. Each ping waits on `pacer`.
+pub async fn discover(
+    targets: &[IpAddr],
+    ping_ports: &[u16],
+    wait: Duration,
+    pacer: &Arc<Pacer>,
+) -> Vec<HostStatus> {
+    let icmp = raw::raw_sockets_available();
+    stream::iter(targets.iter().copied())
+        .map(|ip| ping_host(ip, ping_ports, wait, icmp, pacer))
+        .buffered(DISCOVERY_PARALLELISM)
+        .collect()
+        .await
+}
+
+async fn ping_host(
+    ip: IpAddr,
+    ping_ports: &[u16],
+    wait: Duration,
+    icmp: bool,
+    pacer: &Arc<Pacer>,
+) -> HostStatus {
+    let pacer = &pacer.for_host();
+    // Not spawned, so the pings still in flight are dropped once one answers.
+    let mut pings = FuturesUnordered::new();
+    for &port in ping_ports {
+        pings.push(
+            async move {
+                pacer.wait().await;
+                let sent = Instant::now();
+                let reason = tcp_ping(SocketAddr::new(ip, port), wait).await?;
+                Some((reason, sent.elapsed()))
+            }
+            .boxed(),
+        );
+    }
+    if let (true, IpAddr::V4(target)) = (icmp, ip) {
+        pings.push(
+            async move {
+                pacer.wait().await;
+                let sent = Instant::now();
+                let reason = tokio::task::spawn_blocking(move || icmp_ping(target, wait))
+                    .await
+                    .ok()
+                    .flatten()?;
+                Some((reason, sent.elapsed()))
+            }
+            .boxed(),
+        );
+    }
+
+    while let Some(result) = pings.next().await {
+        if let Some((reason, latency)) = result {
+            return HostStatus {
+                state: HostState::Up,
+                latency: Some(latency),
+                reason,
+            };
+        }
+    }
+    if arp_cached(ip) {
+        return HostStatus {
+            state: HostState::Up,
+            latency: None,
+            reason: "arp-cache",
+        };
+    }
+    HostStatus {
+        state: HostState::Down,
+        latency: None,
+        reason: "no-response",
+    }
+}
+
+/// A completed or refused connection both prove the host is there.
+async fn tcp_ping(addr: SocketAddr, wait: Duration) -> Option<&'static str> {
+    match timeout(wait, source::connect_tcp(addr)).await {
+        Ok(Ok(_)) => Some("syn-ack"),
+        Ok(Err(e)) if e.kind() == ErrorKind::ConnectionRefused => Some("reset"),
+        _ => None,
+    }
+}
+
+fn icmp_ping(target: Ipv4Addr, wait: Duration) -> Option<&'static str> {
+    let socket = RawSocket::icmp().ok()?;
+    let id = raw::random_u32() as u16;
+    socket
+        .send_to(&raw::icmp_echo_request(id, 1, b"portdog"), target)
+        .ok()?;
+    let deadline = Instant::now() + wait;
+    while let Some(packet) = socket.recv_until(deadline) {
+        if let Some(reply) = raw::parse_icmp(&packet)
+            && reply.ip.src == target
+            && reply.is_echo_reply()
+            && reply.id == id
+        {
+            return Some("echo-reply");
+        }
+    }
+    None
+}
+
+/// Whether the kernel's ARP cache holds a complete entry for `ip`, which only happens
+/// for neighbours on a directly attached segment. A passive lookup: the connects above
+/// are what make the kernel resolve the address.
+#[cfg(target_os = "linux")]
+fn arp_cached(ip: IpAddr) -> bool {
+    const ATF_COM: u32 = 0x02;
+    let Ok(table) = std::fs::read_to_string("/proc/net/arp") else {
+        return false;
+    };
+    let ip = ip.to_string();
+    table.lines().skip(1).any(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        fields.len() >= 3
+            && fields[0] == ip
+            && u32::from_str_radix(fields[2].trim_start_matches("0x"), 16)
+                .is_ok_and(|flags| flags & ATF_COM != 0)
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn arp_cached(_ip: IpAddr) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn restores_reasons_from_checkpoints() {
+        let status = HostStatus::restore(HostState::Up, None, "echo-reply");
+        assert_eq!(status.reason, "echo-reply");
+        assert_eq!(
+            HostStatus::restore(HostState::Up, None, "arp-response").reason,
+            "arp-cache"
+        );
+        assert_eq!(
+            HostStatus::restore(HostState::Up, None, "carrier-pigeon").reason,
+            "user-set"
+        );
+    }
+}
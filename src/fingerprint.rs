@@ -1,19 +1,45 @@
+use crate::pacing::HostPacer;
+use crate::services::{self, Protocol};
+use crate::source;
 use once_cell::sync::Lazy;
-use regex::Regex;
-use serde::Serialize;
+use regex::bytes::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
-use tokio::time::timeout;
+use tokio::time::{Instant, timeout, timeout_at};
 use tokio_rustls::TlsConnector;
+use tokio_rustls::client::TlsStream;
 use tokio_rustls::rustls::{
     self, ClientConfig,
     pki_types::{CertificateDer, ServerName, UnixTime},
 };
-const READ_BUFFER_SIZE: usize = 2048;
-const BANNER_TIMEOUT: Duration = Duration::from_secs(4);
+
+mod control_plane;
+mod custom;
+mod ics;
+mod telnet;
+mod udp;
+
+pub use custom::{lint_probes_file, load_probes_file};
+pub use ics::{ICS_TCP_PORTS, ICS_UDP_PORTS, probe_ics_port};
+pub use udp::probe_udp_port;
+
+/// Ports where the service speaks HTTP over TLS, so an HTTP request follows the handshake.
+const HTTPS_PORTS: &[u16] = &[443, 2376, 6443, 8443, 10250];
+/// Ports that expect a TLS handshake before any application data.
+const TLS_PORTS: &[u16] = &[443, 993, 995, 2376, 6443, 8443, 10250];
+
+/// Default cap on how many bytes are collected from a single reply.
+pub const DEFAULT_READ_SIZE: usize = 8192;
+/// Default quiet period after which a partially received reply is considered complete.
+pub const DEFAULT_READ_IDLE_GAP: Duration = Duration::from_millis(300);
+const READ_CHUNK_SIZE: usize = 2048;
+/// Banner of an open TCP port that never answered any probe.
+pub const UNRESPONSIVE_BANNER: &str = "[unresponsive]";
 
 // --- START: Corrected Regex-based matching engine ---
 
@@ -21,39 +47,147 @@ struct Matcher {
     service: &'static str,
     // The struct now holds a *reference* to a static Lazy<Regex>.
     regex: &'static Lazy<Regex>,
+    // A soft match only narrows the service down: probing continues with the
+    // probes and matchers for that service, and it is reported only if nothing
+    // more specific turns up.
+    soft: bool,
+}
+
+/// How confidently a response was identified, from least to most.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum MatchKind {
+    /// Nothing matched; the service name is a guess from the port number.
+    Unmatched,
+    Soft,
+    Hard,
 }
 
-// Step 1: Each Lazy<Regex> is defined as its own static item.
-static SSH_MATCHER: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?i)^SSH-2.0-([^\s]+)").unwrap());
-static HTTP_SERVER_MATCHER: Lazy<Regex> = Lazy::new(|| Regex::new(r"Server: ([^\r\n]+)").unwrap());
-static HTTP_GENERIC_MATCHER: Lazy<Regex> = Lazy::new(|| Regex::new(r"HTTP/\d\.\d").unwrap());
-static FTP_MATCHER: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?i)^220 .*FTP").unwrap());
-static SMTP_MATCHER: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?i)^220 .*SMTP").unwrap());
+// Step 1: Each Lazy<Regex> is defined as its own static item. Patterns run against
+// the raw reply bytes, so binary signatures are written with `\xNN` escapes.
+static SSH_MATCHER: Lazy<Regex> =
+    Lazy::new(|| compile_pattern(r"(?i)^SSH-2.0-([^\r\n]+)").unwrap());
+static SSH_GENERIC_MATCHER: Lazy<Regex> = Lazy::new(|| compile_pattern(r"^SSH-\d").unwrap());
+static HTTP_SERVER_MATCHER: Lazy<Regex> =
+    Lazy::new(|| compile_pattern(r"Server: ([^\r\n]+)").unwrap());
+static HTTP_GENERIC_MATCHER: Lazy<Regex> = Lazy::new(|| compile_pattern(r"HTTP/\d\.\d").unwrap());
+static FTP_MATCHER: Lazy<Regex> = Lazy::new(|| compile_pattern(r"(?i)^220[ -].*FTP").unwrap());
+static SMTP_MATCHER: Lazy<Regex> = Lazy::new(|| compile_pattern(r"(?i)^220[ -].*SMTP").unwrap());
+static POP3_GENERIC_MATCHER: Lazy<Regex> = Lazy::new(|| compile_pattern(r"^\+OK").unwrap());
+static IMAP_GENERIC_MATCHER: Lazy<Regex> =
+    Lazy::new(|| compile_pattern(r"^\* (OK|PREAUTH)").unwrap());
+// NetBIOS session header followed by an SMB1 or SMB2 protocol ID.
+static SMB_MATCHER: Lazy<Regex> =
+    Lazy::new(|| compile_pattern(r"(?s)^\x00.{3}[\xFF\xFE]SMB").unwrap());
+// TPKT header followed by an X.224 connection confirm.
+static RDP_MATCHER: Lazy<Regex> =
+    Lazy::new(|| compile_pattern(r"(?s)^\x03\x00\x00[\x0B\x13]\x0E\xD0").unwrap());
+// TDS tabular result answering a PRELOGIN packet.
+static TDS_MATCHER: Lazy<Regex> =
+    Lazy::new(|| compile_pattern(r"(?s)^\x04\x01\x00.\x00\x00\x01\x00\x00").unwrap());
+// JRMP ProtocolAck carrying the host name the server sees us as.
+static JAVA_RMI_MATCHER: Lazy<Regex> =
+    Lazy::new(|| compile_pattern(r"(?s)^\x4E\x00[\x01-\x40]([\x20-\x7E]+)\x00\x00").unwrap());
+// Protocol 10 handshake packet with a null-terminated server version.
+static MYSQL_MATCHER: Lazy<Regex> =
+    Lazy::new(|| compile_pattern(r"(?s)^.\x00\x00\x00\x0A([\d.]+[\x20-\x7E]*)\x00").unwrap());
 
 // Step 2: The MATCHERS array now holds references to the statics above.
 static MATCHERS: &[Matcher] = &[
     Matcher {
         service: "ssh",
         regex: &SSH_MATCHER,
+        soft: false,
+    },
+    Matcher {
+        service: "ssh",
+        regex: &SSH_GENERIC_MATCHER,
+        soft: true,
     },
     Matcher {
         service: "http",
         regex: &HTTP_SERVER_MATCHER,
+        soft: false,
     },
     Matcher {
         service: "http",
         regex: &HTTP_GENERIC_MATCHER,
+        soft: true,
     },
     Matcher {
         service: "ftp",
         regex: &FTP_MATCHER,
+        soft: false,
     },
     Matcher {
         service: "smtp",
         regex: &SMTP_MATCHER,
+        soft: false,
+    },
+    Matcher {
+        service: "pop3",
+        regex: &POP3_GENERIC_MATCHER,
+        soft: true,
+    },
+    Matcher {
+        service: "imap",
+        regex: &IMAP_GENERIC_MATCHER,
+        soft: true,
+    },
+    Matcher {
+        service: "smb",
+        regex: &SMB_MATCHER,
+        soft: false,
+    },
+    Matcher {
+        service: "ms-wbt-server",
+        regex: &RDP_MATCHER,
+        soft: false,
+    },
+    Matcher {
+        service: "ms-sql-s",
+        regex: &TDS_MATCHER,
+        soft: false,
+    },
+    Matcher {
+        service: "java-rmi",
+        regex: &JAVA_RMI_MATCHER,
+        soft: false,
+    },
+    Matcher {
+        service: "mysql",
+        regex: &MYSQL_MATCHER,
+        soft: false,
     },
 ];
 
+/// Compiles a matcher pattern for byte-oriented matching: Unicode mode is off, so
+/// `\xNN` matches the raw byte and `.` matches any byte except newline.
+fn compile_pattern(pattern: &str) -> Result<Regex, regex::Error> {
+    RegexBuilder::new(pattern).unicode(false).build()
+}
+
+/// A built-in or user-defined matcher with its regex resolved.
+#[derive(Clone, Copy)]
+struct ActiveMatcher {
+    service: &'static str,
+    regex: &'static Regex,
+    soft: bool,
+}
+
+// Step 3: User-defined matchers are merged in ahead of the built-ins, then all are
+// tried most specific first: hard before soft, and within each group those that
+// capture product details before bare signatures.
+static ORDERED_MATCHERS: Lazy<Vec<ActiveMatcher>> = Lazy::new(|| {
+    let built_in = MATCHERS.iter().map(|m| ActiveMatcher {
+        service: m.service,
+        regex: Lazy::force(m.regex),
+        soft: m.soft,
+    });
+    let mut matchers: Vec<ActiveMatcher> = custom::custom_matchers().chain(built_in).collect();
+    matchers.sort_by_key(|m| (m.soft, m.regex.captures_len() <= 1));
+    matchers
+});
+
 // --- END: Corrected Regex-based matching engine ---
 
 #[derive(Debug)]
@@ -94,171 +228,512 @@ impl rustls::client::danger::ServerCertVerifier for InsecureCertificateVerifier
 }
 
 struct Probe {
-    _name: &'static str,
+    name: &'static str,
+    /// Bytes sent once connected; the NULL probe sends nothing and just listens.
     payload: &'static [u8],
+    /// Ports where this probe is likely to succeed; it is tried early and at any intensity there.
     ports: &'static [u16],
+    /// Services this probe can tell apart; empty means any. After a soft match only
+    /// probes that can identify the soft-matched service are still sent.
+    services: &'static [&'static str],
+    /// 1 (sent to almost everything) to 9 (only at high `--version-intensity`).
+    rarity: u8,
+    /// How long to wait for the reply before moving on to the next probe.
+    wait: Duration,
 }
 
 const PROBES: &[Probe] = &[
     Probe {
-        _name: "SMB",
+        name: "NULL",
+        payload: b"",
+        ports: &[],
+        services: &[],
+        rarity: 1,
+        // Banners come straight after the handshake, and a late one is still caught
+        // while GenericLines reuses the connection.
+        wait: Duration::from_secs(2),
+    },
+    Probe {
+        name: "GenericLines",
+        payload: b"\r\n\r\n",
+        ports: &[],
+        services: &[],
+        rarity: 1,
+        wait: Duration::from_secs(3),
+    },
+    Probe {
+        name: "GetRequest",
+        payload: b"GET / HTTP/1.0\r\n\r\n",
+        ports: &[80, 2375, 2379, 8000, 8001, 8080, 8500, 9993, 10255],
+        services: &["http"],
+        rarity: 3,
+        wait: Duration::from_secs(5),
+    },
+    Probe {
+        name: "SMBProgNeg",
         payload: b"\x00\x00\x00\x85\xff\x53\x4d\x42\x72\x00\x00\x00\x00\x18\x53\xc8\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\xff\xfe\x00\x00\x00\x00\x00\x62\x00\x02\x50\x43\x20\x4e\x45\x54\x57\x4f\x52\x4b\x20\x50\x52\x4f\x47\x52\x41\x4d\x20\x31\x2e\x30\x00\x02\x4d\x49\x43\x52\x4f\x53\x4f\x46\x54\x20\x4e\x45\x54\x57\x4f\x52\x4b\x53\x20\x31\x2e\x30\x33\x00\x02\x4d\x49\x43\x52\x4f\x53\x4f\x46\x54\x20\x4e\x45\x54\x57\x4f\x52\x4b\x53\x20\x33\x2e\x30\x00\x02\x4c\x41\x4e\x4d\x41\x4e\x31\x2e\x30\x00\x02\x4c\x4d\x31\x2e\x32\x58\x30\x30\x32\x00\x02\x53\x41\x4d\x42\x41\x00\x02\x4e\x54\x20\x4c\x41\x4e\x4d\x41\x4e\x20\x31\x2e\x30\x00\x02\x4e\x54\x20\x4c\x4d\x20\x30\x2e\x31\x32\x00",
         ports: &[139, 445],
+        services: &["smb", "microsoft-ds", "netbios-ssn"],
+        rarity: 8,
+        wait: Duration::from_secs(3),
     },
     Probe {
-        _name: "RDP",
-        payload: b"\x03\x00\x00\x13\x0e\xe0\x00\x00\x00\x00\x00\x01\x00\x08\x00\x03\x00\x00\x00",
-        ports: &[3389],
+        name: "MSSQLPrelogin",
+        payload: b"\x12\x01\x00\x34\x00\x00\x00\x00\x00\x00\x15\x00\x06\x01\x00\x1b\x00\x01\x02\x00\x1c\x00\x0c\x03\x00\x28\x00\x04\xff\x08\x00\x01\x55\x00\x00\x00\x4d\x53\x53\x51\x4c\x53\x65\x72\x76\x65\x72\x00\x48\x0f\x00\x00",
+        ports: &[1433],
+        services: &["ms-sql-s"],
+        rarity: 7,
+        wait: Duration::from_secs(3),
     },
     Probe {
-        _name: "HTTP",
-        payload: b"GET / HTTP/1.0\r\n\r\n",
-        ports: &[80, 8000, 8080, 9993],
+        name: "JavaRMI",
+        payload: b"JRMI\x00\x02\x4b",
+        ports: &[1099],
+        services: &["java-rmi"],
+        rarity: 8,
+        wait: Duration::from_secs(3),
     },
     Probe {
-        _name: "Generic-Newline",
-        payload: b"\r\n\r\n",
-        ports: &[],
+        name: "TerminalServer",
+        payload: b"\x03\x00\x00\x13\x0e\xe0\x00\x00\x00\x00\x00\x01\x00\x08\x00\x03\x00\x00\x00",
+        ports: &[3389],
+        services: &["ms-wbt-server"],
+        rarity: 8,
+        wait: Duration::from_secs(3),
     },
 ];
 
-#[derive(Debug, Clone, Serialize)]
+/// How long to wait for a TLS handshake to complete.
+const TLS_HANDSHAKE_WAIT: Duration = Duration::from_secs(4);
+
+/// The default `--version-intensity`: everything except the rarest probes.
+pub const DEFAULT_VERSION_INTENSITY: u8 = 7;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Fingerprint {
     pub service_name: String,
     pub banner: String,
+    /// Structured fields decoded from the reply (e.g. SNMP `sysName`, NTP stratum).
+    pub details: BTreeMap<String, String>,
+    /// True when `service_name` comes from the port number rather than the reply.
+    pub guessed: bool,
+    /// True when the port accepted connections but sent nothing to any probe.
+    #[serde(default)]
+    pub silent: bool,
+}
+
+/// Limits on how much and how long a reply is collected once its first bytes arrive.
+#[derive(Debug, Clone, Copy)]
+pub struct ReadLimits {
+    /// Stop once the server has been quiet for this long after sending something.
+    pub idle_gap: Duration,
+    /// Stop once this many bytes have been collected.
+    pub max_size: usize,
+}
+
+impl Default for ReadLimits {
+    fn default() -> Self {
+        ReadLimits {
+            idle_gap: DEFAULT_READ_IDLE_GAP,
+            max_size: DEFAULT_READ_SIZE,
+        }
+    }
+}
+
+/// Options controlling how hard `probe_port` works to identify a service.
+#[derive(Clone)]
+pub struct ProbeConfig {
+    pub version_intensity: u8,
+    pub read_limits: ReadLimits,
+    /// Replaces every probe's own reply wait when set (`--banner-timeout`).
+    pub reply_wait: Option<Duration>,
+    /// Paces each further connection the probes open to the host.
+    pub pacer: Option<Arc<HostPacer>>,
+}
+
+impl ProbeConfig {
+    fn wait_for(&self, probe: &Probe) -> Duration {
+        self.reply_wait.unwrap_or(probe.wait)
+    }
+}
+
+enum ReadOutcome {
+    Data(Vec<u8>),
+    /// Nothing arrived before the wait expired; the connection may still be usable.
+    Silent,
+    /// The peer closed or reset the connection.
+    Closed,
+}
+
+/// How a connection attempt to a TCP port ended.
+pub enum ConnectOutcome {
+    Connected(TcpStream),
+    /// The host answered with a reset.
+    Refused,
+    /// Nothing came back before the timeout.
+    TimedOut,
+    /// Any other error, such as an unreachable network or a local resource limit.
+    Failed,
 }
 
-pub async fn probe_port(addr: SocketAddr, connect_timeout: Duration) -> Option<Fingerprint> {
-    let stream = match timeout(connect_timeout, TcpStream::connect(addr)).await {
-        Ok(Ok(stream)) => stream,
-        _ => return None,
+pub async fn connect_port(addr: SocketAddr, connect_timeout: Duration) -> ConnectOutcome {
+    match timeout(connect_timeout, source::connect_tcp(addr)).await {
+        Ok(Ok(stream)) => ConnectOutcome::Connected(stream),
+        Ok(Err(e)) if e.kind() == std::io::ErrorKind::ConnectionRefused => ConnectOutcome::Refused,
+        Ok(Err(_)) => ConnectOutcome::Failed,
+        Err(_) => ConnectOutcome::TimedOut,
+    }
+}
+
+/// Identifies the service behind `stream`, an open connection to `addr`. Further
+/// connections made by the probes use `connect_timeout`.
+pub async fn probe_port(
+    addr: SocketAddr,
+    stream: TcpStream,
+    connect_timeout: Duration,
+    config: ProbeConfig,
+) -> Option<Fingerprint> {
+    let tls = TLS_PORTS.contains(&addr.port());
+    let fingerprint = if tls {
+        probe_tls(stream, &config).await?
+    } else {
+        probe_cleartext(addr, stream, connect_timeout, &config).await
     };
-    match addr.port() {
-        443 | 993 | 995 => probe_tls(stream).await,
-        _ => probe_cleartext(stream).await,
+    if fingerprint.service_name == "http"
+        && let Some(mut api) =
+            control_plane::probe_control_plane(addr, connect_timeout, tls, &config).await
+    {
+        api.details
+            .insert("http_server".to_string(), fingerprint.banner);
+        return Some(api);
     }
+    Some(fingerprint)
 }
 
-async fn probe_tls(stream: TcpStream) -> Option<Fingerprint> {
-    let addr = stream.peer_addr().ok()?;
-    let port = addr.port();
+async fn connect(
+    addr: SocketAddr,
+    connect_timeout: Duration,
+    config: &ProbeConfig,
+) -> Option<TcpStream> {
+    if let Some(pacer) = &config.pacer {
+        pacer.wait().await;
+    }
+    match timeout(connect_timeout, source::connect_tcp(addr)).await {
+        Ok(Ok(stream)) => Some(stream),
+        _ => None,
+    }
+}
+
+async fn tls_connect(stream: TcpStream) -> Option<TlsStream<TcpStream>> {
     let config = ClientConfig::builder()
         .dangerous()
         .with_custom_certificate_verifier(Arc::new(InsecureCertificateVerifier))
         .with_no_client_auth();
     let connector = TlsConnector::from(Arc::new(config));
     let domain = ServerName::try_from("localhost").unwrap();
-    if let Ok(Ok(mut tls_stream)) = timeout(BANNER_TIMEOUT, connector.connect(domain, stream)).await
-    {
-        if port == 443 {
-            let _ = tls_stream.write_all(b"GET / HTTP/1.0\r\n\r\n").await;
-        }
-        let response_bytes = read_from_stream(&mut tls_stream).await.unwrap_or_default();
-        return Some(analyze_response(&response_bytes, port));
+    timeout(TLS_HANDSHAKE_WAIT, connector.connect(domain, stream))
+        .await
+        .ok()?
+        .ok()
+}
+
+async fn probe_tls(stream: TcpStream, config: &ProbeConfig) -> Option<Fingerprint> {
+    let addr = stream.peer_addr().ok()?;
+    let port = addr.port();
+    if let Some(mut tls_stream) = tls_connect(stream).await {
+        let probe = if HTTPS_PORTS.contains(&port) {
+            probe_named("GetRequest")
+        } else {
+            probe_named("NULL")
+        };
+        let _ = tls_stream.write_all(probe.payload).await;
+        let response_bytes =
+            read_from_stream(&mut tls_stream, config.wait_for(probe), config.read_limits)
+                .await
+                .unwrap_or_default();
+        return Some(analyze_response(&response_bytes, port, Protocol::Tcp));
     }
     Some(Fingerprint {
         service_name: "tls".to_string(),
         banner: "Could not complete TLS handshake".to_string(),
+        ..Default::default()
     })
 }
 
-async fn probe_cleartext(mut stream: TcpStream) -> Option<Fingerprint> {
-    let addr = stream.peer_addr().ok()?;
+impl Probe {
+    fn identifies(&self, service: &str) -> bool {
+        self.services.is_empty() || self.services.contains(&service)
+    }
+
+    /// NULL, GenericLines and other rarity-1 probes that every port gets.
+    fn is_catch_all(&self) -> bool {
+        self.ports.is_empty() && self.rarity == 1
+    }
+}
+
+fn probe_named(name: &str) -> &'static Probe {
+    PROBES
+        .iter()
+        .find(|p| p.name == name)
+        .expect("built-in probe exists")
+}
+
+/// The probes to try against `port`, most likely first: the NULL probe, then the
+/// probes registered for the port, then everything else within `version_intensity`.
+fn probes_for_port(port: u16, version_intensity: u8) -> Vec<&'static Probe> {
+    let mut probes: Vec<&Probe> = custom::custom_probes()
+        .chain(PROBES)
+        .filter(|p| {
+            p.payload.is_empty() || p.ports.contains(&port) || p.rarity <= version_intensity
+        })
+        .collect();
+    probes.sort_by_key(|p| (!p.payload.is_empty(), !p.ports.contains(&port), p.rarity));
+    probes
+}
+
+async fn probe_cleartext(
+    addr: SocketAddr,
+    stream: TcpStream,
+    connect_timeout: Duration,
+    config: &ProbeConfig,
+) -> Fingerprint {
     let port = addr.port();
-    if let Some(response_bytes) = read_from_stream(&mut stream).await {
-        if !response_bytes.is_empty() {
-            return Some(analyze_response(&response_bytes, port));
+    let mut stream = Some(stream);
+    let mut best: Option<(Fingerprint, MatchKind)> = None;
+    let probes = probes_for_port(port, config.version_intensity);
+    let mut catch_alls_left = probes.iter().filter(|p| p.is_catch_all()).count();
+    for probe in probes {
+        // Once the catch-all probes and every probe meant for the port have run, a
+        // reply nothing matched is the banner we report; rarer probes won't improve it.
+        if catch_alls_left == 0 && matches!(best, Some((_, MatchKind::Unmatched))) {
+            break;
         }
-    }
-    let applicable_probes = PROBES.iter().filter(|p| p.ports.contains(&port));
-    for probe in applicable_probes {
-        if stream.write_all(probe.payload).await.is_ok() {
-            if let Some(response_bytes) = read_from_stream(&mut stream).await {
-                return Some(analyze_response(&response_bytes, port));
-            }
+        if probe.is_catch_all() {
+            catch_alls_left -= 1;
         }
-    }
-    let fallback_probes = PROBES.iter().filter(|p| p.ports.is_empty());
-    for probe in fallback_probes {
-        if stream.write_all(probe.payload).await.is_ok() {
-            if let Some(response_bytes) = read_from_stream(&mut stream).await {
-                return Some(analyze_response(&response_bytes, port));
+        let soft_service = match &best {
+            Some((fingerprint, MatchKind::Soft)) => Some(fingerprint.service_name.clone()),
+            _ => None,
+        };
+        if let Some(service) = &soft_service
+            && !probe.identifies(service)
+        {
+            continue;
+        }
+        // A connection is only reused while nothing has been written to it, so an
+        // earlier probe the server did not understand can't confuse this one.
+        let mut current = match stream.take() {
+            Some(current) => current,
+            None => match connect(addr, connect_timeout, config).await {
+                Some(current) => current,
+                None => continue,
+            },
+        };
+        if !probe.payload.is_empty() && current.write_all(probe.payload).await.is_err() {
+            continue;
+        }
+        match read_reply(&mut current, config.wait_for(probe), config.read_limits).await {
+            ReadOutcome::Data(response_bytes) => {
+                let (fingerprint, kind) = analyze_stream_response(
+                    &mut current,
+                    response_bytes,
+                    port,
+                    config.wait_for(probe),
+                    config.read_limits,
+                    soft_service.as_deref(),
+                )
+                .await;
+                if kind == MatchKind::Hard {
+                    return fingerprint;
+                }
+                if best.as_ref().is_none_or(|(_, best_kind)| kind > *best_kind) {
+                    best = Some((fingerprint, kind));
+                }
             }
+            ReadOutcome::Silent if probe.payload.is_empty() => stream = Some(current),
+            ReadOutcome::Silent | ReadOutcome::Closed => {}
         }
     }
-    Some(Fingerprint {
-        service_name: get_service_name_from_port(port).to_string(),
-        banner: "[unresponsive]".to_string(),
-    })
+    match best {
+        Some((fingerprint, _)) => fingerprint,
+        None => Fingerprint {
+            service_name: services::service_name(port, Protocol::Tcp),
+            banner: UNRESPONSIVE_BANNER.to_string(),
+            guessed: true,
+            silent: true,
+            ..Default::default()
+        },
+    }
 }
 
-async fn read_from_stream<S>(stream: &mut S) -> Option<Vec<u8>>
+/// Like `analyze_response`, but may keep talking to the server first when the
+/// reply turns out to be a Telnet option negotiation.
+async fn analyze_stream_response(
+    stream: &mut TcpStream,
+    response_bytes: Vec<u8>,
+    port: u16,
+    wait: Duration,
+    limits: ReadLimits,
+    service_filter: Option<&str>,
+) -> (Fingerprint, MatchKind) {
+    if telnet::is_negotiation(&response_bytes) {
+        let fingerprint =
+            telnet::fingerprint_telnet(stream, response_bytes, port, wait, limits).await;
+        return (fingerprint, MatchKind::Hard);
+    }
+    classify_response(&response_bytes, port, Protocol::Tcp, service_filter)
+}
+
+/// Collects a reply until the server goes quiet for `limits.idle_gap`, a
+/// protocol-specific terminator is seen, `limits.max_size` is reached or `wait`
+/// (the probe's overall deadline) runs out.
+async fn read_reply<S>(stream: &mut S, wait: Duration, limits: ReadLimits) -> ReadOutcome
 where
     S: AsyncReadExt + Unpin,
 {
-    let mut buffer = vec![0; READ_BUFFER_SIZE];
-    match timeout(BANNER_TIMEOUT, stream.read(&mut buffer)).await {
-        Ok(Ok(bytes_read)) if bytes_read > 0 => {
-            buffer.truncate(bytes_read);
-            Some(buffer)
+    let deadline = Instant::now() + wait;
+    let mut response = Vec::new();
+    let mut buffer = vec![0; READ_CHUNK_SIZE.min(limits.max_size.max(1))];
+    loop {
+        let read_deadline = if response.is_empty() {
+            deadline
+        } else {
+            deadline.min(Instant::now() + limits.idle_gap)
+        };
+        match timeout_at(read_deadline, stream.read(&mut buffer)).await {
+            Ok(Ok(bytes_read)) if bytes_read > 0 => {
+                response.extend_from_slice(&buffer[..bytes_read]);
+                if response.len() >= limits.max_size {
+                    response.truncate(limits.max_size);
+                    break;
+                }
+                if response_complete(&response) {
+                    break;
+                }
+            }
+            Ok(_) if response.is_empty() => return ReadOutcome::Closed,
+            Err(_) if response.is_empty() => return ReadOutcome::Silent,
+            _ => break,
         }
-        _ => None,
     }
+    ReadOutcome::Data(response)
 }
 
-fn analyze_response(response_bytes: &[u8], port: u16) -> Fingerprint {
-    if [139, 445].contains(&port)
-        && response_bytes.starts_with(&[0x00, 0x00])
-        && response_bytes.windows(4).any(|window| window == b"\xFFSMB")
-    {
-        return Fingerprint {
-            service_name: "smb".to_string(),
-            banner: format!(
-                "[SMB Response: {} bytes] {}",
-                response_bytes.len(),
-                to_hex_string(response_bytes)
-            ),
-        };
+/// Recognises replies that are known to be complete, so common protocols don't
+/// have to sit through the idle gap.
+fn response_complete(data: &[u8]) -> bool {
+    // TPKT (RDP, S7) and NetBIOS session (SMB) frames carry their own length.
+    if let [0x03, 0x00, high, low, ..] = data {
+        return data.len() >= u16::from_be_bytes([*high, *low]) as usize;
     }
-    match std::str::from_utf8(response_bytes) {
-        Ok(banner_str) => analyze_text_banner(banner_str, port),
-        Err(_) => {
-            let service_name = get_service_name_from_port(port).to_string();
-            let banner = format!(
-                "[Binary data: {} bytes] {}",
-                response_bytes.len(),
-                to_hex_string(response_bytes)
-            );
-            Fingerprint {
-                service_name,
-                banner,
-            }
+    // A NetBIOS length is 17 bits, and a reply longer than it says is something else
+    // that happens to start with a zero byte.
+    if let [0x00, flags @ (0 | 1), high, low, ..] = data {
+        let length = 4 + u32::from_be_bytes([0, *flags, *high, *low]) as usize;
+        if data.len() <= length {
+            return data.len() == length;
         }
     }
+    // HTTP: the headers are all the banner we need.
+    if data.starts_with(b"HTTP/") {
+        return data.windows(4).any(|window| window == b"\r\n\r\n");
+    }
+    if !data.ends_with(b"\n") {
+        return false;
+    }
+    let text = String::from_utf8_lossy(data);
+    let last_line = text.trim_end().lines().last().unwrap_or("").trim_start();
+    // FTP/SMTP style "220-" continuation lines end with a "220 " line.
+    match last_line.as_bytes() {
+        [a, b, c, sep, ..] if [a, b, c].iter().all(|d| d.is_ascii_digit()) => *sep == b' ',
+        [a, b, c] if [a, b, c].iter().all(|d| d.is_ascii_digit()) => true,
+        _ => ["SSH-", "+OK", "-ERR", "* OK", "* PREAUTH", "* BYE"]
+            .iter()
+            .any(|prefix| last_line.starts_with(prefix)),
+    }
+}
+
+async fn read_from_stream<S>(stream: &mut S, wait: Duration, limits: ReadLimits) -> Option<Vec<u8>>
+where
+    S: AsyncReadExt + Unpin,
+{
+    match read_reply(stream, wait, limits).await {
+        ReadOutcome::Data(bytes) => Some(bytes),
+        ReadOutcome::Silent | ReadOutcome::Closed => None,
+    }
 }
 
-fn analyze_text_banner(banner: &str, port: u16) -> Fingerprint {
-    let banner_trimmed = banner.trim();
+fn analyze_response(response_bytes: &[u8], port: u16, protocol: Protocol) -> Fingerprint {
+    classify_response(response_bytes, port, protocol, None).0
+}
 
-    for matcher in MATCHERS {
-        if let Some(captures) = matcher.regex.captures(banner) {
-            let info = captures.get(1).map_or("", |m| m.as_str()).trim();
+/// Identifies a response, only consulting matchers for `service_filter` when an
+/// earlier soft match has already narrowed the service down.
+fn classify_response(
+    response_bytes: &[u8],
+    port: u16,
+    protocol: Protocol,
+    service_filter: Option<&str>,
+) -> (Fingerprint, MatchKind) {
+    if let Some(result) = match_banner(response_bytes, service_filter) {
+        return result;
+    }
+    let banner = match std::str::from_utf8(response_bytes) {
+        Ok(banner_str) => banner_str.trim().lines().next().unwrap_or("").to_string(),
+        Err(_) => binary_summary(response_bytes),
+    };
+    let fingerprint = Fingerprint {
+        service_name: services::service_name(port, protocol),
+        banner,
+        guessed: true,
+        ..Default::default()
+    };
+    (fingerprint, MatchKind::Unmatched)
+}
 
-            return Fingerprint {
+fn match_banner(
+    response_bytes: &[u8],
+    service_filter: Option<&str>,
+) -> Option<(Fingerprint, MatchKind)> {
+    let matchers = ORDERED_MATCHERS
+        .iter()
+        .filter(|m| service_filter.is_none_or(|service| m.service == service));
+    for matcher in matchers {
+        if let Some(captures) = matcher.regex.captures(response_bytes) {
+            let info = captures
+                .get(1)
+                .map(|m| String::from_utf8_lossy(m.as_bytes()).trim().to_string())
+                .unwrap_or_default();
+
+            let banner = if !info.is_empty() {
+                info
+            } else if let Ok(text) = std::str::from_utf8(response_bytes) {
+                text.lines().next().unwrap_or("").to_string()
+            } else {
+                binary_summary(response_bytes)
+            };
+            let fingerprint = Fingerprint {
                 service_name: matcher.service.to_string(),
-                banner: if info.is_empty() {
-                    banner.lines().next().unwrap_or("").to_string()
-                } else {
-                    info.to_string()
-                },
+                banner,
+                ..Default::default()
             };
+            let kind = if matcher.soft {
+                MatchKind::Soft
+            } else {
+                MatchKind::Hard
+            };
+            return Some((fingerprint, kind));
         }
     }
+    None
+}
 
-    Fingerprint {
-        service_name: get_service_name_from_port(port).to_string(),
-        banner: banner_trimmed.lines().next().unwrap_or("").to_string(),
-    }
+fn binary_summary(bytes: &[u8]) -> String {
+    format!(
+        "[Binary data: {} bytes] {}",
+        bytes.len(),
+        to_hex_string(bytes)
+    )
 }
 
 fn to_hex_string(bytes: &[u8]) -> String {
@@ -273,27 +748,95 @@ fn to_hex_string(bytes: &[u8]) -> String {
     hex_str.trim_end().to_string()
 }
 
-fn get_service_name_from_port(port: u16) -> &'static str {
-    match port {
-        21 => "ftp",
-        22 => "ssh",
-        23 => "telnet",
-        25 => "smtp",
-        53 => "dns",
-        80 => "http",
-        110 => "pop3",
-        139 => "netbios-ssn",
-        143 => "imap",
-        443 => "https",
-        445 => "microsoft-ds",
-        993 => "imaps",
-        995 => "pop3s",
-        1433 => "mssql",
-        3306 => "mysql",
-        3389 => "ms-wbt-server",
-        5432 => "postgresql",
-        6379 => "redis",
-        27017 => "mongodb",
-        _ => "unknown",
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn identify(response: &[u8]) -> (String, String, MatchKind) {
+        let (fingerprint, kind) = classify_response(response, 1, Protocol::Tcp, None);
+        (fingerprint.service_name, fingerprint.banner, kind)
+    }
+
+    #[test]
+    fn matches_text_banners_with_product_details() {
+        let (service, banner, kind) = identify(b"SSH-2.0-OpenSSH_9.6p1 Ubuntu-3\r\n");
+        assert_eq!((service.as_str(), kind), ("ssh", MatchKind::Hard));
+        assert_eq!(banner, "OpenSSH_9.6p1 Ubuntu-3");
+
+        let (service, banner, _) =
+            identify(b"HTTP/1.1 200 OK\r\nServer: nginx/1.24.0\r\nContent-Length: 0\r\n\r\n");
+        assert_eq!(
+            (service.as_str(), banner.as_str()),
+            ("http", "nginx/1.24.0")
+        );
+    }
+
+    #[test]
+    fn matches_binary_signatures() {
+        let smb2 = b"\x00\x00\x00\x40\xFESMB\x40\x00\x00\x00";
+        assert_eq!(identify(smb2).0, "smb");
+
+        let rdp = b"\x03\x00\x00\x13\x0E\xD0\x00\x00\x12\x34\x00\x02\x00\x08\x00\x02\x00\x00\x00";
+        assert_eq!(identify(rdp).0, "ms-wbt-server");
+
+        let mysql = b"\x4A\x00\x00\x00\x0A8.0.36-0ubuntu0.22.04.1\x00\x08\x00\x00\x00";
+        let (service, banner, kind) = identify(mysql);
+        assert_eq!((service.as_str(), kind), ("mysql", MatchKind::Hard));
+        assert_eq!(banner, "8.0.36-0ubuntu0.22.04.1");
+
+        let rmi = b"\x4E\x00\x0B192.168.0.5\x00\x00\xC3\x50";
+        assert_eq!(identify(rmi).1, "192.168.0.5");
+    }
+
+    #[test]
+    fn binary_escapes_match_raw_bytes_not_code_points() {
+        // With Unicode on, \xFE would match the UTF-8 encoding of U+00FE instead.
+        let utf8_lookalike = "\u{0}\u{0}\u{0}\u{40}\u{FE}SMB".as_bytes();
+        assert_ne!(identify(utf8_lookalike).0, "smb");
+    }
+
+    #[test]
+    fn unmatched_binary_replies_are_summarised_as_hex() {
+        let (_, banner, kind) = identify(&[0xDE, 0xAD, 0xBE, 0xEF]);
+        assert_eq!(kind, MatchKind::Unmatched);
+        assert_eq!(banner, "[Binary data: 4 bytes] DE AD BE EF");
+    }
+
+    #[test]
+    fn length_framed_replies_complete_at_their_length() {
+        let tpkt = b"\x03\x00\x00\x0B\x06\xD0\x00\x00\x12\x34\x00";
+        assert!(response_complete(tpkt));
+        assert!(!response_complete(&tpkt[..8]));
+
+        let netbios = b"\x00\x00\x00\x04\xFFSMB";
+        assert!(response_complete(netbios));
+        assert!(!response_complete(&netbios[..6]));
+    }
+
+    #[test]
+    fn netbios_lookalikes_fall_through_to_text_rules() {
+        // Longer than its "length" says, so not a NetBIOS frame.
+        assert!(response_complete(b"\x00\x00\x00\x01\n220 ready\n"));
+        assert!(!response_complete(b"\x00\x00\x00\x01\n220-more"));
+    }
+
+    #[test]
+    fn http_completes_after_the_headers() {
+        assert!(!response_complete(b"HTTP/1.1 200 OK\r\nServer: x\r\n"));
+        assert!(response_complete(
+            b"HTTP/1.1 200 OK\r\nServer: x\r\n\r\n<html>"
+        ));
+    }
+
+    #[test]
+    fn multiline_greetings_complete_on_the_final_line() {
+        assert!(!response_complete(b"220-mail.example.com ESMTP\r\n"));
+        assert!(response_complete(
+            b"220-mail.example.com ESMTP\r\n220 ready\r\n"
+        ));
+        assert!(response_complete(b"SSH-2.0-OpenSSH_9.6\r\n"));
+        assert!(response_complete(b"* OK IMAP4rev1 ready\r\n"));
+        assert!(!response_complete(b"Welcome to the server\n"));
+        assert!(!response_complete(b"+OK POP3 ready"));
     }
 }
@@ -0,0 +1,186 @@
+use super::{Fingerprint, ProbeConfig, connect, tls_connect};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::{Instant, timeout_at};
+
+/// Upper bound on an API response body; `/v1/agent/self` in particular is large.
+const MAX_API_RESPONSE_SIZE: usize = 256 * 1024;
+
+/// How long to wait for a complete API response.
+const API_RESPONSE_WAIT: Duration = Duration::from_secs(4);
+
+/// Endpoints tried in order; the first one whose response has a recognisable shape wins.
+const API_PATHS: &[&str] = &["/version", "/v1/agent/self", "/v2/keys", "/pods"];
+
+struct ApiResponse {
+    status: u16,
+    body: String,
+}
+
+/// Follows up on an HTTP(S) port with requests to well-known container and
+/// orchestration API endpoints, identifying Docker, Kubernetes, etcd and Consul.
+pub(super) async fn probe_control_plane(
+    addr: SocketAddr,
+    connect_timeout: Duration,
+    tls: bool,
+    config: &ProbeConfig,
+) -> Option<Fingerprint> {
+    for path in API_PATHS {
+        let Some(response) = http_get(addr, path, connect_timeout, tls, config).await else {
+            continue;
+        };
+        if let Some(fingerprint) = recognise(path, &response) {
+            return Some(fingerprint);
+        }
+    }
+    None
+}
+
+async fn http_get(
+    addr: SocketAddr,
+    path: &str,
+    connect_timeout: Duration,
+    tls: bool,
+    config: &ProbeConfig,
+) -> Option<ApiResponse> {
+    let stream = connect(addr, connect_timeout, config).await?;
+    let request = format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\nAccept: application/json\r\nUser-Agent: PortDog\r\n\r\n",
+        path, addr
+    );
+    let raw = if tls {
+        let mut tls_stream = tls_connect(stream).await?;
+        send_and_read_all(&mut tls_stream, request.as_bytes()).await?
+    } else {
+        let mut stream = stream;
+        send_and_read_all(&mut stream, request.as_bytes()).await?
+    };
+
+    let raw = String::from_utf8_lossy(&raw);
+    let (head, body) = raw.split_once("\r\n\r\n")?;
+    let status = head
+        .lines()
+        .next()?
+        .split_whitespace()
+        .nth(1)?
+        .parse()
+        .ok()?;
+    Some(ApiResponse {
+        status,
+        body: body.to_string(),
+    })
+}
+
+async fn send_and_read_all<S>(stream: &mut S, request: &[u8]) -> Option<Vec<u8>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    stream.write_all(request).await.ok()?;
+    let deadline = Instant::now() + API_RESPONSE_WAIT;
+    let mut response = Vec::new();
+    let mut buffer = [0; 8192];
+    while response.len() < MAX_API_RESPONSE_SIZE {
+        match timeout_at(deadline, stream.read(&mut buffer)).await {
+            Ok(Ok(bytes_read)) if bytes_read > 0 => {
+                response.extend_from_slice(&buffer[..bytes_read])
+            }
+            _ => break,
+        }
+    }
+    (!response.is_empty()).then_some(response)
+}
+
+fn recognise(path: &str, response: &ApiResponse) -> Option<Fingerprint> {
+    let json: Option<Value> = serde_json::from_str(&response.body).ok();
+    let unauthenticated = (200..300).contains(&response.status);
+    let auth_required = matches!(response.status, 401 | 403);
+
+    let (service, product, version, mut details) = match (path, &json) {
+        ("/version", Some(json)) if json.get("ApiVersion").is_some() => {
+            let mut details = BTreeMap::new();
+            insert_str(&mut details, "api_version", json.get("ApiVersion"));
+            insert_str(&mut details, "os", json.get("Os"));
+            insert_str(&mut details, "kernel", json.get("KernelVersion"));
+            ("docker", "Docker", str_field(json, "Version"), details)
+        }
+        ("/version", Some(json)) if json.get("gitVersion").is_some() => {
+            let mut details = BTreeMap::new();
+            insert_str(&mut details, "platform", json.get("platform"));
+            insert_str(&mut details, "go_version", json.get("goVersion"));
+            (
+                "kubernetes",
+                "kube-apiserver",
+                str_field(json, "gitVersion"),
+                details,
+            )
+        }
+        ("/version", Some(json)) if json.get("etcdserver").is_some() => {
+            let mut details = BTreeMap::new();
+            insert_str(&mut details, "cluster_version", json.get("etcdcluster"));
+            ("etcd", "etcd", str_field(json, "etcdserver"), details)
+        }
+        // The API server rejects anonymous requests with a v1 Status object.
+        (_, Some(json))
+            if auth_required && json.get("kind").and_then(Value::as_str) == Some("Status") =>
+        {
+            ("kubernetes", "kube-apiserver", None, BTreeMap::new())
+        }
+        ("/v1/agent/self", Some(json)) if json.get("Config").is_some() => {
+            let config = &json["Config"];
+            let mut details = BTreeMap::new();
+            insert_str(&mut details, "datacenter", config.get("Datacenter"));
+            insert_str(&mut details, "node_name", config.get("NodeName"));
+            ("consul", "Consul", str_field(config, "Version"), details)
+        }
+        ("/v2/keys", Some(json)) if json.get("action").is_some() && json.get("node").is_some() => {
+            ("etcd", "etcd", None, BTreeMap::new())
+        }
+        ("/pods", Some(json)) if json.get("kind").and_then(Value::as_str) == Some("PodList") => {
+            let mut details = BTreeMap::new();
+            if let Some(pods) = json.get("items").and_then(Value::as_array) {
+                details.insert("pods".to_string(), pods.len().to_string());
+            }
+            ("kubernetes", "kubelet", None, details)
+        }
+        // The kubelet answers anonymous requests with a bare "Unauthorized".
+        ("/pods", None) if auth_required && response.body.trim() == "Unauthorized" => {
+            ("kubernetes", "kubelet", None, BTreeMap::new())
+        }
+        _ => return None,
+    };
+
+    details.insert("product".to_string(), product.to_string());
+    details.insert("endpoint".to_string(), path.to_string());
+    details.insert("unauthenticated".to_string(), unauthenticated.to_string());
+    if let Some(version) = &version {
+        details.insert("version".to_string(), version.clone());
+    }
+    let mut banner = match version {
+        Some(version) => format!("{} {}", product, version),
+        None => product.to_string(),
+    };
+    banner.push_str(if unauthenticated {
+        " [unauthenticated API access]"
+    } else {
+        " [authentication required]"
+    });
+    Some(Fingerprint {
+        service_name: service.to_string(),
+        banner,
+        details,
+        ..Default::default()
+    })
+}
+
+fn str_field(json: &Value, key: &str) -> Option<String> {
+    json.get(key).and_then(Value::as_str).map(str::to_string)
+}
+
+fn insert_str(details: &mut BTreeMap<String, String>, key: &str, value: Option<&Value>) {
+    if let Some(value) = value.and_then(Value::as_str) {
+        details.insert(key.to_string(), value.to_string());
+    }
+}
@@ -0,0 +1,353 @@
+use super::{ActiveMatcher, PROBES, Probe, compile_pattern};
+use serde::Deserialize;
+use std::path::Path;
+use std::sync::OnceLock;
+use std::time::Duration;
+use toml::Spanned;
+
+/// Probes and matchers loaded from `--probes-file`, installed once before scanning.
+static CUSTOM: OnceLock<CustomDefinitions> = OnceLock::new();
+
+const DEFAULT_CUSTOM_RARITY: u8 = 5;
+const DEFAULT_CUSTOM_WAIT_MS: u64 = 3000;
+
+#[derive(Default)]
+struct CustomDefinitions {
+    probes: Vec<&'static Probe>,
+    matchers: Vec<ActiveMatcher>,
+}
+
+/// The on-disk format:
+///
+/// ```toml
+/// [[probe]]
+/// name = "AcmeHello"
+/// payload = 'HELLO\r\n'
+/// ports = [9000]
+/// services = ["acme"]
+///
+/// [[match]]
+/// service = "acme"
+/// pattern = '^ACME v([\d.]+)'
+/// ```
+///
+/// Patterns match the raw reply bytes, so `\xNN` matches a single byte.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProbesFile {
+    #[serde(default, rename = "probe")]
+    probes: Vec<ProbeDefinition>,
+    #[serde(default, rename = "match")]
+    matchers: Vec<MatchDefinition>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProbeDefinition {
+    name: Spanned<String>,
+    /// C-style escapes (`\r`, `\n`, `\t`, `\0`, `\\`, `\xNN`) are decoded.
+    payload: Spanned<String>,
+    #[serde(default)]
+    ports: Vec<u16>,
+    #[serde(default)]
+    services: Vec<String>,
+    rarity: Option<Spanned<u8>>,
+    wait_ms: Option<Spanned<u64>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MatchDefinition {
+    service: Spanned<String>,
+    pattern: Spanned<String>,
+    #[serde(default)]
+    soft: bool,
+}
+
+/// Validates and installs the definitions in `path`, returning how many probes and
+/// matchers were added. Must be called before the first port is probed.
+pub fn load_probes_file(path: &Path) -> Result<(usize, usize), Vec<String>> {
+    let definitions = parse_probes_file(path)?;
+    let counts = (definitions.probes.len(), definitions.matchers.len());
+    if CUSTOM.set(definitions).is_err() {
+        return Err(vec!["probe definitions were already loaded".to_string()]);
+    }
+    Ok(counts)
+}
+
+/// Validates `path` without installing anything, collecting every problem found.
+pub fn lint_probes_file(path: &Path) -> Result<(usize, usize), Vec<String>> {
+    parse_probes_file(path).map(|d| (d.probes.len(), d.matchers.len()))
+}
+
+pub(super) fn custom_probes() -> impl Iterator<Item = &'static Probe> {
+    CUSTOM
+        .get()
+        .into_iter()
+        .flat_map(|d| d.probes.iter().copied())
+}
+
+pub(super) fn custom_matchers() -> impl Iterator<Item = ActiveMatcher> {
+    CUSTOM
+        .get()
+        .into_iter()
+        .flat_map(|d| d.matchers.iter().copied())
+}
+
+fn parse_probes_file(path: &Path) -> Result<CustomDefinitions, Vec<String>> {
+    let display = path.display();
+    let source = std::fs::read_to_string(path)
+        .map_err(|e| vec![format!("{}: could not read file: {}", display, e)])?;
+    let file: ProbesFile = toml::from_str(&source).map_err(|e| {
+        let line = e.span().map_or(0, |span| line_of(&source, span.start));
+        let message = e.message().lines().collect::<Vec<_>>().join("; ");
+        vec![format!("{}:{}: {}", display, line, message)]
+    })?;
+
+    let mut errors = Vec::new();
+    let mut definitions = CustomDefinitions::default();
+    let mut error_at = |span: std::ops::Range<usize>, message: String| {
+        errors.push(format!(
+            "{}:{}: {}",
+            display,
+            line_of(&source, span.start),
+            message
+        ));
+    };
+
+    for probe in file.probes {
+        let name = probe.name.get_ref().trim();
+        if name.is_empty() {
+            error_at(
+                probe.name.span(),
+                "probe name must not be empty".to_string(),
+            );
+            continue;
+        }
+        if PROBES.iter().any(|p| p.name == name)
+            || definitions.probes.iter().any(|p| p.name == name)
+        {
+            error_at(
+                probe.name.span(),
+                format!("duplicate probe name '{}'", name),
+            );
+            continue;
+        }
+        let payload = match unescape_payload(probe.payload.get_ref()) {
+            // An empty payload would just repeat the built-in NULL probe.
+            Ok(payload) if payload.is_empty() => {
+                error_at(
+                    probe.payload.span(),
+                    format!("payload for probe '{}' must not be empty", name),
+                );
+                continue;
+            }
+            Ok(payload) => payload,
+            Err(e) => {
+                error_at(
+                    probe.payload.span(),
+                    format!("invalid payload for probe '{}': {}", name, e),
+                );
+                continue;
+            }
+        };
+        let rarity = match &probe.rarity {
+            Some(rarity) if !(1..=9).contains(rarity.get_ref()) => {
+                error_at(
+                    rarity.span(),
+                    format!("rarity for probe '{}' must be between 1 and 9", name),
+                );
+                continue;
+            }
+            Some(rarity) => *rarity.get_ref(),
+            None => DEFAULT_CUSTOM_RARITY,
+        };
+        let wait_ms = match &probe.wait_ms {
+            Some(wait_ms) if *wait_ms.get_ref() == 0 => {
+                error_at(
+                    wait_ms.span(),
+                    format!("wait_ms for probe '{}' must be greater than 0", name),
+                );
+                continue;
+            }
+            Some(wait_ms) => *wait_ms.get_ref(),
+            None => DEFAULT_CUSTOM_WAIT_MS,
+        };
+        let services: Vec<&'static str> = probe.services.into_iter().map(leak_str).collect();
+        definitions.probes.push(Box::leak(Box::new(Probe {
+            name: leak_str(name.to_string()),
+            payload: Box::leak(payload.into_boxed_slice()),
+            ports: Box::leak(probe.ports.into_boxed_slice()),
+            services: Box::leak(services.into_boxed_slice()),
+            rarity,
+            wait: Duration::from_millis(wait_ms),
+        })));
+    }
+
+    for matcher in file.matchers {
+        let service = matcher.service.get_ref().trim();
+        if service.is_empty() {
+            error_at(
+                matcher.service.span(),
+                "service must not be empty".to_string(),
+            );
+            continue;
+        }
+        let pattern = matcher.pattern.get_ref();
+        match compile_pattern(pattern) {
+            Ok(regex) => definitions.matchers.push(ActiveMatcher {
+                service: leak_str(service.to_string()),
+                regex: Box::leak(Box::new(regex)),
+                soft: matcher.soft,
+            }),
+            Err(e) => error_at(
+                matcher.pattern.span(),
+                format!(
+                    "invalid regex '{}' for service '{}': {}",
+                    pattern,
+                    service,
+                    regex_error_summary(&e)
+                ),
+            ),
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(definitions)
+    } else {
+        Err(errors)
+    }
+}
+
+fn regex_error_summary(error: &regex::Error) -> String {
+    let message = error.to_string();
+    let last_line = message.lines().last().unwrap_or_default().trim();
+    last_line.trim_start_matches("error: ").to_string()
+}
+
+fn line_of(source: &str, offset: usize) -> usize {
+    source[..offset.min(source.len())].matches('\n').count() + 1
+}
+
+fn leak_str(s: String) -> &'static str {
+    Box::leak(s.into_boxed_str())
+}
+
+fn unescape_payload(payload: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::with_capacity(payload.len());
+    let mut chars = payload.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        match chars.next() {
+            Some('r') => bytes.push(b'\r'),
+            Some('n') => bytes.push(b'\n'),
+            Some('t') => bytes.push(b'\t'),
+            Some('0') => bytes.push(0),
+            Some('\\') => bytes.push(b'\\'),
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                let byte = u8::from_str_radix(&hex, 16)
+                    .ok()
+                    .filter(|_| hex.len() == 2)
+                    .ok_or_else(|| format!("invalid hex escape '\\x{}'", hex))?;
+                bytes.push(byte);
+            }
+            Some(other) => return Err(format!("unknown escape '\\{}'", other)),
+            None => return Err("trailing backslash".to_string()),
+        }
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(source: &str) -> Result<CustomDefinitions, Vec<String>> {
+        let path = std::env::temp_dir().join(format!(
+            "portdog-probes-{}-{}.toml",
+            std::process::id(),
+            source.len()
+        ));
+        std::fs::write(&path, source).unwrap();
+        let result = parse_probes_file(&path);
+        std::fs::remove_file(&path).unwrap();
+        result.map_err(|errors| {
+            let prefix = format!("{}:", path.display());
+            errors
+                .into_iter()
+                .map(|e| e.trim_start_matches(&prefix).to_string())
+                .collect()
+        })
+    }
+
+    #[test]
+    fn unescapes_c_style_payloads() {
+        assert_eq!(
+            unescape_payload(r"HELLO\r\n\t\0\\\x7f\xFFé").unwrap(),
+            b"HELLO\r\n\t\0\\\x7f\xFF\xC3\xA9"
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_escapes() {
+        assert_eq!(
+            unescape_payload(r"\x4").unwrap_err(),
+            r"invalid hex escape '\x4'"
+        );
+        assert_eq!(
+            unescape_payload(r"\xZZ").unwrap_err(),
+            r"invalid hex escape '\xZZ'"
+        );
+        assert_eq!(unescape_payload(r"\q").unwrap_err(), r"unknown escape '\q'");
+        assert_eq!(unescape_payload("abc\\").unwrap_err(), "trailing backslash");
+    }
+
+    #[test]
+    fn parses_probes_and_matchers() {
+        let definitions = parse(
+            "[[probe]]\nname = \"AcmeHello\"\npayload = 'HELLO\\r\\n'\nports = [9000]\nservices = [\"acme\"]\n\n[[match]]\nservice = \"acme\"\npattern = '^ACME v([\\d.]+)'\n",
+        )
+        .unwrap();
+        let probe = definitions.probes[0];
+        assert_eq!(probe.payload, b"HELLO\r\n");
+        assert_eq!((probe.ports, probe.services), (&[9000][..], &["acme"][..]));
+        assert_eq!(probe.rarity, DEFAULT_CUSTOM_RARITY);
+        let matcher = definitions.matchers[0];
+        assert_eq!(matcher.service, "acme");
+        assert!(matcher.regex.is_match(b"ACME v1.2\r\n"));
+    }
+
+    #[test]
+    fn reports_every_error_with_its_line() {
+        let errors = parse(
+            "[[probe]]\nname = \"GetRequest\"\npayload = 'x'\n\n[[probe]]\nname = \"Empty\"\npayload = ''\n\n[[probe]]\nname = \"Rare\"\npayload = 'x'\nrarity = 10\n\n[[match]]\nservice = \"acme\"\npattern = '('\n",
+        )
+        .err()
+        .unwrap();
+        assert_eq!(errors.len(), 4, "{:?}", errors);
+        assert_eq!(errors[0], "2: duplicate probe name 'GetRequest'");
+        assert_eq!(errors[1], "7: payload for probe 'Empty' must not be empty");
+        assert_eq!(
+            errors[2],
+            "12: rarity for probe 'Rare' must be between 1 and 9"
+        );
+        assert!(errors[3].starts_with("16: invalid regex '(' for service 'acme'"));
+    }
+
+    #[test]
+    fn rejects_unknown_fields() {
+        let errors = parse("[[match]]\nservice = \"acme\"\nregex = 'x'\n")
+            .err()
+            .unwrap();
+        assert!(
+            errors[0].starts_with("3: unknown field `regex`"),
+            "{:?}",
+            errors
+        );
+    }
+}
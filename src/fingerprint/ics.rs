@@ -0,0 +1,474 @@
+use super::udp::connect_udp;
+use super::{Fingerprint, ReadLimits, read_from_stream};
+use crate::source;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::time::{Instant, timeout, timeout_at};
+
+const MODBUS_PORT: u16 = 502;
+const S7_PORT: u16 = 102;
+const ENIP_PORT: u16 = 44818;
+const DNP3_PORT: u16 = 20000;
+const BACNET_PORT: u16 = 47808;
+
+/// Controllers can be slow to answer; this is independent of the polite connect timeout.
+const ICS_REPLY_WAIT: Duration = Duration::from_secs(4);
+
+/// Ports that get a dedicated read-only ICS probe when `--ics` is given.
+pub const ICS_TCP_PORTS: &[u16] = &[S7_PORT, MODBUS_PORT, DNP3_PORT, ENIP_PORT];
+pub const ICS_UDP_PORTS: &[u16] = &[ENIP_PORT, BACNET_PORT];
+
+// Modbus/TCP: MBAP header (transaction 1, protocol 0, length 5, unit 0) followed by
+// Read Device Identification (FC 0x2B / MEI 0x0E), basic category, from object 0.
+const MODBUS_READ_DEVICE_ID: &[u8] = b"\x00\x01\x00\x00\x00\x05\x00\x2b\x0e\x01\x00";
+
+// COTP connection request to rack 0 / slot 2, the usual S7-300/400 CPU location.
+const S7_COTP_CONNECT_SLOT2: &[u8] =
+    b"\x03\x00\x00\x16\x11\xe0\x00\x00\x00\x01\x00\xc0\x01\x0a\xc1\x02\x01\x00\xc2\x02\x01\x02";
+// Fallback TSAP used by S7-1200/1500 CPUs.
+const S7_COTP_CONNECT_SLOT0: &[u8] =
+    b"\x03\x00\x00\x16\x11\xe0\x00\x00\x00\x01\x00\xc0\x01\x0a\xc1\x02\x01\x00\xc2\x02\x02\x00";
+const S7_SETUP_COMMUNICATION: &[u8] = b"\x03\x00\x00\x19\x02\xf0\x80\x32\x01\x00\x00\x00\x00\x00\x08\x00\x00\xf0\x00\x00\x01\x00\x01\x01\xe0";
+// SZL 0x0011 (module identification) and 0x001C (component identification).
+const S7_READ_SZL_0011: &[u8] = b"\x03\x00\x00\x21\x02\xf0\x80\x32\x07\x00\x00\x00\x00\x00\x08\x00\x08\x00\x01\x12\x04\x11\x44\x01\x00\xff\x09\x00\x04\x00\x11\x00\x01";
+const S7_READ_SZL_001C: &[u8] = b"\x03\x00\x00\x21\x02\xf0\x80\x32\x07\x00\x00\x00\x00\x00\x08\x00\x08\x00\x01\x12\x04\x11\x44\x01\x00\xff\x09\x00\x04\x00\x1c\x00\x01";
+
+// EtherNet/IP encapsulation header for the ListIdentity command (0x0063).
+const ENIP_LIST_IDENTITY: &[u8] =
+    b"\x63\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00";
+
+// BACnet/IP unicast Who-Is.
+const BACNET_WHO_IS: &[u8] = b"\x81\x0a\x00\x0c\x01\x20\xff\xff\x00\xff\x10\x08";
+
+// Device object properties read from the wildcard instance 4194303.
+const BACNET_PROPERTIES: &[(u8, &str)] = &[
+    (121, "vendor"),
+    (70, "product"),
+    (44, "firmware"),
+    (12, "application_software"),
+    (77, "object_name"),
+];
+
+pub async fn probe_ics_port(
+    addr: SocketAddr,
+    connect_timeout: Duration,
+    udp: bool,
+    limits: ReadLimits,
+) -> Option<Fingerprint> {
+    if udp {
+        return match addr.port() {
+            BACNET_PORT => probe_bacnet(addr, connect_timeout).await,
+            ENIP_PORT => probe_enip_udp(addr, connect_timeout).await,
+            _ => None,
+        };
+    }
+    let mut stream = match timeout(connect_timeout, source::connect_tcp(addr)).await {
+        Ok(Ok(stream)) => stream,
+        _ => return None,
+    };
+    let fingerprint = match addr.port() {
+        MODBUS_PORT => probe_modbus(&mut stream, limits).await,
+        S7_PORT => match probe_s7(&mut stream, S7_COTP_CONNECT_SLOT2, limits).await {
+            Some(fingerprint) => Some(fingerprint),
+            None => {
+                let mut stream = match timeout(connect_timeout, source::connect_tcp(addr)).await {
+                    Ok(Ok(stream)) => stream,
+                    _ => return None,
+                };
+                probe_s7(&mut stream, S7_COTP_CONNECT_SLOT0, limits).await
+            }
+        },
+        ENIP_PORT => exchange(&mut stream, ENIP_LIST_IDENTITY, limits)
+            .await
+            .and_then(|reply| decode_enip_list_identity(&reply)),
+        DNP3_PORT => probe_dnp3(&mut stream, limits).await,
+        _ => None,
+    };
+    Some(fingerprint.unwrap_or_else(|| Fingerprint {
+        service_name: ics_service_name(addr.port()).to_string(),
+        banner: "[no ICS identification reply]".to_string(),
+        guessed: true,
+        ..Default::default()
+    }))
+}
+
+fn ics_service_name(port: u16) -> &'static str {
+    match port {
+        MODBUS_PORT => "modbus",
+        S7_PORT => "s7comm",
+        ENIP_PORT => "enip",
+        DNP3_PORT => "dnp3",
+        BACNET_PORT => "bacnet",
+        _ => "unknown",
+    }
+}
+
+async fn exchange(stream: &mut TcpStream, request: &[u8], limits: ReadLimits) -> Option<Vec<u8>> {
+    stream.write_all(request).await.ok()?;
+    read_from_stream(stream, ICS_REPLY_WAIT, limits).await
+}
+
+fn ics_fingerprint(service_name: &str, details: BTreeMap<String, String>) -> Fingerprint {
+    let banner = ["vendor", "product", "firmware"]
+        .iter()
+        .filter_map(|key| details.get(*key))
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .join(" ");
+    Fingerprint {
+        service_name: service_name.to_string(),
+        banner,
+        details,
+        ..Default::default()
+    }
+}
+
+fn read_cstring(data: &[u8], offset: usize) -> Option<String> {
+    let field = data.get(offset..)?;
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    let value = String::from_utf8_lossy(&field[..end]).trim().to_string();
+    (!value.is_empty()).then_some(value)
+}
+
+// --- Modbus/TCP ---
+
+async fn probe_modbus(stream: &mut TcpStream, limits: ReadLimits) -> Option<Fingerprint> {
+    let reply = exchange(stream, MODBUS_READ_DEVICE_ID, limits).await?;
+    // The reply must echo our transaction and protocol identifiers.
+    if reply.len() < 9 || reply[..4] != MODBUS_READ_DEVICE_ID[..4] {
+        return None;
+    }
+    let mut details = BTreeMap::new();
+    details.insert("unit_id".to_string(), reply[6].to_string());
+    if reply[7] & 0x80 != 0 {
+        details.insert("exception".to_string(), format!("0x{:02X}", reply[8]));
+        return Some(Fingerprint {
+            service_name: "modbus".to_string(),
+            banner: "Modbus/TCP (device identification not supported)".to_string(),
+            details,
+            ..Default::default()
+        });
+    }
+    if reply.len() < 14 || reply[7] != 0x2B || reply[8] != 0x0E {
+        return None;
+    }
+    let num_objects = reply[13] as usize;
+    let mut offset = 14;
+    for _ in 0..num_objects {
+        let (&object_id, &len) = (reply.get(offset)?, reply.get(offset + 1)?);
+        let value = reply.get(offset + 2..offset + 2 + len as usize)?;
+        offset += 2 + len as usize;
+        let key = match object_id {
+            0x00 => "vendor",
+            0x01 => "product_code",
+            0x02 => "firmware",
+            0x03 => "vendor_url",
+            0x04 => "product",
+            0x05 => "model",
+            _ => continue,
+        };
+        details.insert(
+            key.to_string(),
+            String::from_utf8_lossy(value).trim().to_string(),
+        );
+    }
+    if !details.contains_key("product")
+        && let Some(code) = details.get("product_code").cloned()
+    {
+        details.insert("product".to_string(), code);
+    }
+    Some(ics_fingerprint("modbus", details))
+}
+
+// --- Siemens S7comm ---
+
+async fn probe_s7(
+    stream: &mut TcpStream,
+    cotp_connect: &[u8],
+    limits: ReadLimits,
+) -> Option<Fingerprint> {
+    // A COTP connection confirm (0xD0) is required before any S7 traffic.
+    let reply = exchange(stream, cotp_connect, limits).await?;
+    if reply.get(5) != Some(&0xD0) {
+        return None;
+    }
+    let reply = exchange(stream, S7_SETUP_COMMUNICATION, limits).await?;
+    if reply.get(7) != Some(&0x32) {
+        return None;
+    }
+
+    let mut details = BTreeMap::new();
+    details.insert("vendor".to_string(), "Siemens".to_string());
+    if let Some(reply) = exchange(stream, S7_READ_SZL_0011, limits).await
+        && reply.get(7) == Some(&0x32)
+    {
+        if let Some(module) = read_cstring(&reply, 43) {
+            details.insert("product".to_string(), module);
+        }
+        if let Some(hardware) = read_cstring(&reply, 71) {
+            details.insert("hardware".to_string(), hardware);
+        }
+        if let Some(version) = reply.get(122..125) {
+            details.insert(
+                "firmware".to_string(),
+                format!("v{}.{}.{}", version[0], version[1], version[2]),
+            );
+        }
+    }
+    if let Some(reply) = exchange(stream, S7_READ_SZL_001C, limits).await
+        && reply.get(7) == Some(&0x32)
+    {
+        // Some CPUs answer with a four byte shorter header.
+        let shift = if reply.get(30) == Some(&0x1C) { 0 } else { 4 };
+        for (key, offset) in [
+            ("system_name", 39),
+            ("module_type", 73),
+            ("plant_id", 107),
+            ("copyright", 141),
+            ("serial", 175),
+        ] {
+            if let Some(value) = read_cstring(&reply, offset + shift) {
+                details.insert(key.to_string(), value);
+            }
+        }
+    }
+    Some(ics_fingerprint("s7comm", details))
+}
+
+// --- EtherNet/IP ---
+
+async fn probe_enip_udp(addr: SocketAddr, reply_timeout: Duration) -> Option<Fingerprint> {
+    let socket = connect_udp(addr).await?;
+    socket.send(ENIP_LIST_IDENTITY).await.ok()?;
+    let mut buffer = vec![0; 1024];
+    let bytes_read = timeout(reply_timeout, socket.recv(&mut buffer))
+        .await
+        .ok()?
+        .ok()?;
+    decode_enip_list_identity(&buffer[..bytes_read])
+}
+
+fn decode_enip_list_identity(reply: &[u8]) -> Option<Fingerprint> {
+    // Command 0x0063 with one CIP Identity item (type 0x000C).
+    if reply.len() < 63 || reply[..2] != [0x63, 0x00] || reply[26..28] != [0x0C, 0x00] {
+        return None;
+    }
+    let le16 = |offset: usize| u16::from_le_bytes([reply[offset], reply[offset + 1]]);
+    let vendor_id = le16(48);
+    let device_type = le16(50);
+    let serial = u32::from_le_bytes([reply[58], reply[59], reply[60], reply[61]]);
+    let name_len = reply[62] as usize;
+
+    let mut details = BTreeMap::new();
+    details.insert("vendor_id".to_string(), vendor_id.to_string());
+    if let Some(vendor) = enip_vendor_name(vendor_id) {
+        details.insert("vendor".to_string(), vendor.to_string());
+    }
+    details.insert(
+        "device_type".to_string(),
+        match device_type {
+            0x00 => "Generic Device".to_string(),
+            0x02 => "AC Drive".to_string(),
+            0x0C => "Communications Adapter".to_string(),
+            0x0E => "Programmable Logic Controller".to_string(),
+            other => format!("0x{:04X}", other),
+        },
+    );
+    details.insert("product_code".to_string(), le16(52).to_string());
+    details.insert(
+        "firmware".to_string(),
+        format!("{}.{:03}", reply[54], reply[55]),
+    );
+    details.insert("serial".to_string(), format!("0x{:08X}", serial));
+    if let Some(name) = reply.get(63..63 + name_len) {
+        details.insert(
+            "product".to_string(),
+            String::from_utf8_lossy(name).trim().to_string(),
+        );
+    }
+    Some(ics_fingerprint("enip", details))
+}
+
+/// Common vendor IDs from the ODVA registry.
+fn enip_vendor_name(vendor_id: u16) -> Option<&'static str> {
+    Some(match vendor_id {
+        1 => "Rockwell Automation/Allen-Bradley",
+        3 => "Honeywell",
+        5 => "Rockwell Automation/Reliance Electric",
+        8 => "Molex",
+        12 => "Banner Engineering",
+        26 => "Festo",
+        40 => "WAGO",
+        44 => "Yaskawa Electric America",
+        46 => "ABB Industrial Systems",
+        47 => "Omron",
+        48 => "Turck",
+        57 => "Pepperl+Fuchs",
+        68 => "Eaton Electrical",
+        82 => "Mitsubishi Electric Automation",
+        90 => "HMS Industrial Networks",
+        243 => "Schneider Automation",
+        283 => "Hilscher",
+        _ => return None,
+    })
+}
+
+// --- BACnet/IP ---
+
+async fn probe_bacnet(addr: SocketAddr, reply_timeout: Duration) -> Option<Fingerprint> {
+    let socket = connect_udp(addr).await?;
+    socket.send(BACNET_WHO_IS).await.ok()?;
+    for (invoke_id, (property, _)) in BACNET_PROPERTIES.iter().enumerate() {
+        socket
+            .send(&bacnet_read_property(invoke_id as u8, *property))
+            .await
+            .ok()?;
+    }
+
+    let mut details = BTreeMap::new();
+    let deadline = Instant::now() + reply_timeout;
+    let mut buffer = vec![0; 1500];
+    let mut answered = false;
+    while let Ok(Ok(bytes_read)) = timeout_at(deadline, socket.recv(&mut buffer)).await {
+        answered |= decode_bacnet(&buffer[..bytes_read], &mut details);
+        if details.len() > BACNET_PROPERTIES.len() {
+            break;
+        }
+    }
+    answered.then(|| ics_fingerprint("bacnet", details))
+}
+
+fn bacnet_read_property(invoke_id: u8, property: u8) -> Vec<u8> {
+    let mut apdu = vec![
+        0x00, 0x05, invoke_id, 0x0C, // Confirmed ReadProperty
+        0x0C, 0x02, 0x3F, 0xFF, 0xFF, // Device object, instance 4194303
+        0x19, property,
+    ];
+    let mut packet = vec![0x81, 0x0A, 0x00, 0x00, 0x01, 0x04];
+    packet.append(&mut apdu);
+    let len = (packet.len() as u16).to_be_bytes();
+    packet[2..4].copy_from_slice(&len);
+    packet
+}
+
+/// Decodes an I-Am or ReadProperty-ACK into `details`, returning false for anything else.
+fn decode_bacnet(packet: &[u8], details: &mut BTreeMap<String, String>) -> bool {
+    if packet.len() < 6 || packet[0] != 0x81 || packet[4] != 0x01 {
+        return false;
+    }
+    let control = packet[5];
+    if control & 0x80 != 0 {
+        return false;
+    }
+    let mut offset = 6;
+    if control & 0x20 != 0 {
+        let Some(&dlen) = packet.get(offset + 2) else {
+            return false;
+        };
+        offset += 3 + dlen as usize;
+    }
+    if control & 0x08 != 0 {
+        let Some(&slen) = packet.get(offset + 2) else {
+            return false;
+        };
+        offset += 3 + slen as usize;
+    }
+    if control & 0x20 != 0 {
+        offset += 1;
+    }
+    let Some(apdu) = packet.get(offset..) else {
+        return false;
+    };
+
+    match apdu {
+        // Unconfirmed I-Am: device object identifier, max APDU, segmentation, vendor.
+        [0x10, 0x00, 0xC4, id @ ..] if id.len() >= 4 => {
+            let instance = u32::from_be_bytes([id[0], id[1], id[2], id[3]]) & 0x003F_FFFF;
+            details.insert("device_instance".to_string(), instance.to_string());
+            let mut tags = &id[4..];
+            for index in 0..3 {
+                let Some((&tag, rest)) = tags.split_first() else {
+                    break;
+                };
+                let len = (tag & 0x07) as usize;
+                let Some(value) = rest.get(..len) else {
+                    break;
+                };
+                if index == 2 {
+                    let vendor_id = value.iter().fold(0u32, |acc, &b| (acc << 8) | b as u32);
+                    details.insert("vendor_id".to_string(), vendor_id.to_string());
+                }
+                tags = &rest[len..];
+            }
+            true
+        }
+        // ReadProperty-ACK: invoke ID, object identifier, property, opening tag 3.
+        [0x30, invoke_id, 0x0C, rest @ ..]
+            if rest.len() > 8 && rest[5] == 0x19 && rest[7] == 0x3E =>
+        {
+            let Some((_, key)) = BACNET_PROPERTIES.get(*invoke_id as usize) else {
+                return false;
+            };
+            if let Some(value) = bacnet_character_string(&rest[8..]) {
+                details.insert(key.to_string(), value);
+            }
+            true
+        }
+        _ => false,
+    }
+}
+
+fn bacnet_character_string(data: &[u8]) -> Option<String> {
+    let (&tag, rest) = data.split_first()?;
+    if tag >> 4 != 7 {
+        return None;
+    }
+    let (len, rest) = match tag & 0x07 {
+        5 => (*rest.first()? as usize, rest.get(1..)?),
+        len => (len as usize, rest),
+    };
+    // The first content byte is the character set; 0 is UTF-8.
+    let value = rest.get(1..len)?;
+    Some(String::from_utf8_lossy(value).trim().to_string())
+}
+
+// --- DNP3 ---
+
+async fn probe_dnp3(stream: &mut TcpStream, limits: ReadLimits) -> Option<Fingerprint> {
+    // Request Link Status (FC 9) from master 3 to the self-address 0xFFFC.
+    let mut request = vec![0x05, 0x64, 0x05, 0xC9, 0xFC, 0xFF, 0x03, 0x00];
+    let crc = dnp3_crc(&request);
+    request.extend_from_slice(&crc.to_le_bytes());
+    let reply = exchange(stream, &request, limits).await?;
+    if reply.len() < 10 || reply[..2] != [0x05, 0x64] {
+        return None;
+    }
+    let source = u16::from_le_bytes([reply[6], reply[7]]);
+    let mut details = BTreeMap::new();
+    details.insert("source_address".to_string(), source.to_string());
+    details.insert("function".to_string(), format!("0x{:02X}", reply[3] & 0x0F));
+    Some(Fingerprint {
+        service_name: "dnp3".to_string(),
+        banner: format!("DNP3 outstation address {}", source),
+        details,
+        ..Default::default()
+    })
+}
+
+fn dnp3_crc(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &byte in data {
+        crc ^= byte as u16;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xA6BC
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
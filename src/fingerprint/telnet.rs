@@ -0,0 +1,148 @@
+use super::{Fingerprint, ReadLimits, match_banner, read_from_stream};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+
+const IAC: u8 = 255;
+const DONT: u8 = 254;
+const DO: u8 = 253;
+const WONT: u8 = 252;
+const WILL: u8 = 251;
+const SB: u8 = 250;
+const SE: u8 = 240;
+
+/// How many negotiation round trips to sit through before giving up on a prompt.
+const MAX_NEGOTIATION_ROUNDS: usize = 8;
+
+/// Returns true if `bytes` opens with a Telnet IAC command.
+pub(super) fn is_negotiation(bytes: &[u8]) -> bool {
+    matches!(bytes, [IAC, SB..=DONT, ..])
+}
+
+/// Refuses every option the server offers or requests, strips the IAC sequences
+/// and keeps reading until the server sends printable text such as a login prompt.
+pub(super) async fn fingerprint_telnet<S>(
+    stream: &mut S,
+    initial: Vec<u8>,
+    port: u16,
+    wait: Duration,
+    limits: ReadLimits,
+) -> Fingerprint
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut text = Vec::new();
+    let mut pending = initial;
+    for _ in 0..MAX_NEGOTIATION_ROUNDS {
+        let (data, replies) = strip_negotiation(&pending);
+        text.extend(data);
+        if !replies.is_empty() && stream.write_all(&replies).await.is_err() {
+            break;
+        }
+        if text.iter().any(|b| !b.is_ascii_whitespace()) {
+            break;
+        }
+        match read_from_stream(stream, wait, limits).await {
+            Some(bytes) => pending = bytes,
+            None => break,
+        }
+    }
+
+    if let Some((fingerprint, _)) = match_banner(&text, None) {
+        return fingerprint;
+    }
+    let text = String::from_utf8_lossy(&text);
+    let banner = text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n");
+    Fingerprint {
+        service_name: "telnet".to_string(),
+        banner: if banner.is_empty() {
+            format!("[telnet negotiation only on port {}]", port)
+        } else {
+            banner
+        },
+        ..Default::default()
+    }
+}
+
+/// Splits a chunk of Telnet traffic into its data bytes and the refusals to send back.
+fn strip_negotiation(bytes: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let mut data = Vec::new();
+    let mut replies = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != IAC {
+            data.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+        match bytes.get(i + 1) {
+            Some(&IAC) => {
+                data.push(IAC);
+                i += 2;
+            }
+            Some(&command @ WILL..=DONT) => {
+                if let Some(&option) = bytes.get(i + 2) {
+                    match command {
+                        DO => replies.extend_from_slice(&[IAC, WONT, option]),
+                        WILL => replies.extend_from_slice(&[IAC, DONT, option]),
+                        _ => {}
+                    }
+                }
+                i += 3;
+            }
+            Some(&SB) => {
+                // Skip the subnegotiation up to and including IAC SE.
+                i += 2;
+                while i < bytes.len() && !(bytes[i] == IAC && bytes.get(i + 1) == Some(&SE)) {
+                    i += 1;
+                }
+                i += 2;
+            }
+            _ => i += 2,
+        }
+    }
+    (data, replies)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_offered_and_requested_options() {
+        // DO TERMINAL-TYPE, WILL ECHO, WONT LINEMODE, then the prompt.
+        let input = [&[IAC, DO, 24, IAC, WILL, 1, IAC, WONT, 34][..], b"login: "].concat();
+        let (data, replies) = strip_negotiation(&input);
+        assert_eq!(data, b"login: ");
+        assert_eq!(replies, [IAC, WONT, 24, IAC, DONT, 1]);
+    }
+
+    #[test]
+    fn skips_subnegotiation_and_unescapes_iac() {
+        let input = [&[IAC, SB, 24, 1, IAC, SE][..], b"a", &[IAC, IAC], b"b"].concat();
+        let (data, replies) = strip_negotiation(&input);
+        assert_eq!(data, [b'a', IAC, b'b']);
+        assert!(replies.is_empty());
+    }
+
+    #[test]
+    fn truncated_commands_are_dropped() {
+        let (data, replies) = strip_negotiation(&[b'x', IAC, DO]);
+        assert_eq!(data, b"x");
+        assert!(replies.is_empty());
+        let (data, _) = strip_negotiation(&[IAC, SB, 24, 1]);
+        assert!(data.is_empty());
+    }
+
+    #[test]
+    fn detects_negotiation_openers() {
+        assert!(is_negotiation(&[IAC, DO, 1]));
+        assert!(is_negotiation(&[IAC, SB, 24]));
+        assert!(!is_negotiation(&[IAC, SE]));
+        assert!(!is_negotiation(b"SSH-2.0-OpenSSH"));
+    }
+}
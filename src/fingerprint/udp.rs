@@ -0,0 +1,606 @@
+use super::{Fingerprint, analyze_response};
+use crate::services::Protocol;
+use crate::source;
+use std::collections::BTreeMap;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+
+const UDP_READ_BUFFER_SIZE: usize = 4096;
+
+const SNMP_COMMUNITIES: &[&str] = &["public", "private"];
+
+// sysDescr.0, sysObjectID.0 and sysName.0 from the MIB-2 system group.
+const SNMP_SYS_DESCR: &[u8] = &[0x2B, 6, 1, 2, 1, 1, 1, 0];
+const SNMP_SYS_OBJECT_ID: &[u8] = &[0x2B, 6, 1, 2, 1, 1, 2, 0];
+const SNMP_SYS_NAME: &[u8] = &[0x2B, 6, 1, 2, 1, 1, 5, 0];
+
+struct UdpProbe {
+    ports: &'static [u16],
+    payloads: fn() -> Vec<Vec<u8>>,
+    decode: fn(&[u8]) -> Option<Fingerprint>,
+}
+
+const UDP_PROBES: &[UdpProbe] = &[
+    UdpProbe {
+        ports: &[161],
+        payloads: snmp_payloads,
+        decode: decode_snmp,
+    },
+    UdpProbe {
+        ports: &[123],
+        payloads: ntp_payloads,
+        decode: decode_ntp,
+    },
+    UdpProbe {
+        ports: &[1900],
+        payloads: ssdp_payloads,
+        decode: decode_ssdp,
+    },
+    UdpProbe {
+        ports: &[137],
+        payloads: netbios_payloads,
+        decode: decode_netbios,
+    },
+];
+
+/// Sends the service-specific payloads for `addr`'s port (or an empty datagram if
+/// none are known) and decodes the first reply. Returns `None` when the port
+/// answers with an ICMP unreachable or stays silent.
+pub async fn probe_udp_port(addr: SocketAddr, reply_timeout: Duration) -> Option<Fingerprint> {
+    let socket = connect_udp(addr).await?;
+
+    let port = addr.port();
+    let probe = UDP_PROBES.iter().find(|p| p.ports.contains(&port));
+    let payloads = probe.map_or_else(|| vec![Vec::new()], |p| (p.payloads)());
+    for payload in &payloads {
+        socket.send(payload).await.ok()?;
+    }
+
+    let mut buffer = vec![0; UDP_READ_BUFFER_SIZE];
+    let bytes_read = match timeout(reply_timeout, socket.recv(&mut buffer)).await {
+        Ok(Ok(bytes_read)) => bytes_read,
+        _ => return None,
+    };
+    buffer.truncate(bytes_read);
+    Some(analyze_udp_response(&buffer, port, probe))
+}
+
+/// Binds a local socket from the configured source and connects it to `addr`, so ICMP
+/// port unreachable errors surface on `recv`.
+pub(super) async fn connect_udp(addr: SocketAddr) -> Option<UdpSocket> {
+    let socket = source::udp_socket(addr).await.ok()?;
+    socket.connect(addr).await.ok()?;
+    Some(socket)
+}
+
+/// Decodes a reply with the decoder of the probe that was sent, if any. Other decoders
+/// are not tried: a reply answers what was sent, and loose checks such as NTP's would
+/// otherwise claim unrelated replies.
+fn analyze_udp_response(response_bytes: &[u8], port: u16, sent: Option<&UdpProbe>) -> Fingerprint {
+    sent.and_then(|probe| (probe.decode)(response_bytes))
+        .unwrap_or_else(|| analyze_response(response_bytes, port, Protocol::Udp))
+}
+
+// --- SNMP ---
+
+fn snmp_payloads() -> Vec<Vec<u8>> {
+    let mut payloads = Vec::new();
+    for community in SNMP_COMMUNITIES {
+        // SNMPv2c (1) first, then SNMPv1 (0) for older agents.
+        for version in [1, 0] {
+            payloads.push(snmp_get_request(version, community));
+        }
+    }
+    payloads
+}
+
+fn snmp_get_request(version: u8, community: &str) -> Vec<u8> {
+    let varbinds: Vec<u8> = [SNMP_SYS_DESCR, SNMP_SYS_OBJECT_ID, SNMP_SYS_NAME]
+        .iter()
+        .flat_map(|oid| ber_tlv(0x30, &[ber_tlv(0x06, oid), vec![0x05, 0x00]].concat()))
+        .collect();
+    let pdu = [
+        ber_tlv(0x02, &[0x50, 0x44]),
+        ber_tlv(0x02, &[0x00]),
+        ber_tlv(0x02, &[0x00]),
+        ber_tlv(0x30, &varbinds),
+    ]
+    .concat();
+    let message = [
+        ber_tlv(0x02, &[version]),
+        ber_tlv(0x04, community.as_bytes()),
+        ber_tlv(0xA0, &pdu),
+    ]
+    .concat();
+    ber_tlv(0x30, &message)
+}
+
+fn decode_snmp(data: &[u8]) -> Option<Fingerprint> {
+    let (0x30, message, _) = ber_read(data)? else {
+        return None;
+    };
+    let (0x02, version, rest) = ber_read(message)? else {
+        return None;
+    };
+    let (0x04, community, rest) = ber_read(rest)? else {
+        return None;
+    };
+    let (0xA2, pdu, _) = ber_read(rest)? else {
+        return None;
+    };
+    let (_, _request_id, rest) = ber_read(pdu)?;
+    let (_, _error_status, rest) = ber_read(rest)?;
+    let (_, _error_index, rest) = ber_read(rest)?;
+    let (0x30, mut varbinds, _) = ber_read(rest)? else {
+        return None;
+    };
+
+    let mut details = BTreeMap::new();
+    let version = match version {
+        [0] => "1",
+        [1] => "2c",
+        _ => "unknown",
+    };
+    details.insert("version".to_string(), version.to_string());
+    details.insert(
+        "community".to_string(),
+        String::from_utf8_lossy(community).into_owned(),
+    );
+
+    while let Some((0x30, varbind, rest)) = ber_read(varbinds) {
+        varbinds = rest;
+        let Some((0x06, oid, value)) = ber_read(varbind) else {
+            continue;
+        };
+        let Some((tag, value, _)) = ber_read(value) else {
+            continue;
+        };
+        let key = match oid {
+            SNMP_SYS_DESCR => "sysDescr",
+            SNMP_SYS_OBJECT_ID => "sysObjectID",
+            SNMP_SYS_NAME => "sysName",
+            _ => continue,
+        };
+        let value = match tag {
+            0x04 => String::from_utf8_lossy(value).trim().to_string(),
+            0x06 => decode_oid(value),
+            // noSuchObject / noSuchInstance / endOfMibView.
+            _ => continue,
+        };
+        details.insert(key.to_string(), value);
+    }
+
+    let banner = match details.get("sysDescr") {
+        Some(descr) => descr.lines().next().unwrap_or("").to_string(),
+        None => format!(
+            "SNMPv{} (community: {})",
+            details["version"], details["community"]
+        ),
+    };
+    Some(Fingerprint {
+        service_name: "snmp".to_string(),
+        banner,
+        details,
+        ..Default::default()
+    })
+}
+
+fn ber_tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = content.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let len_bytes: Vec<u8> = len
+            .to_be_bytes()
+            .into_iter()
+            .skip_while(|&b| b == 0)
+            .collect();
+        out.push(0x80 | len_bytes.len() as u8);
+        out.extend(len_bytes);
+    }
+    out.extend_from_slice(content);
+    out
+}
+
+/// Reads one BER TLV, returning its tag, value and the bytes that follow it.
+fn ber_read(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = data.split_first()?;
+    let (&first_len, rest) = rest.split_first()?;
+    let (len, rest) = if first_len < 0x80 {
+        (first_len as usize, rest)
+    } else {
+        let num_bytes = (first_len & 0x7F) as usize;
+        if num_bytes == 0 || num_bytes > 4 || rest.len() < num_bytes {
+            return None;
+        }
+        let len = rest[..num_bytes]
+            .iter()
+            .fold(0usize, |acc, &b| (acc << 8) | b as usize);
+        (len, &rest[num_bytes..])
+    };
+    if rest.len() < len {
+        return None;
+    }
+    Some((tag, &rest[..len], &rest[len..]))
+}
+
+fn decode_oid(data: &[u8]) -> String {
+    let Some((&first, rest)) = data.split_first() else {
+        return String::new();
+    };
+    let mut arcs = vec![(first / 40) as u64, (first % 40) as u64];
+    let mut value = 0u64;
+    for &byte in rest {
+        value = (value << 7) | (byte & 0x7F) as u64;
+        if byte & 0x80 == 0 {
+            arcs.push(value);
+            value = 0;
+        }
+    }
+    arcs.iter()
+        .map(|arc| arc.to_string())
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+// --- NTP ---
+
+fn ntp_payloads() -> Vec<Vec<u8>> {
+    // LI=3 (unsynchronised), VN=4, Mode=3 (client), followed by zeroed fields.
+    let mut packet = vec![0; 48];
+    packet[0] = 0xE3;
+    vec![packet]
+}
+
+fn decode_ntp(data: &[u8]) -> Option<Fingerprint> {
+    if data.len() < 48 {
+        return None;
+    }
+    let version = (data[0] >> 3) & 0x07;
+    let mode = data[0] & 0x07;
+    // Only server (4) and broadcast (5) replies are meaningful here.
+    if !(1..=4).contains(&version) || !(4..=5).contains(&mode) {
+        return None;
+    }
+    let stratum = data[1];
+    let refid_bytes = &data[12..16];
+    let refid = if stratum <= 1 {
+        // Stratum 0 carries a kiss code, stratum 1 a reference source such as "GPS".
+        String::from_utf8_lossy(refid_bytes)
+            .trim_end_matches('\0')
+            .to_string()
+    } else {
+        Ipv4Addr::new(
+            refid_bytes[0],
+            refid_bytes[1],
+            refid_bytes[2],
+            refid_bytes[3],
+        )
+        .to_string()
+    };
+
+    let mut details = BTreeMap::new();
+    details.insert("version".to_string(), version.to_string());
+    details.insert("stratum".to_string(), stratum.to_string());
+    details.insert("refid".to_string(), refid.clone());
+    Some(Fingerprint {
+        service_name: "ntp".to_string(),
+        banner: format!(
+            "NTPv{} server, stratum {}, refid {}",
+            version, stratum, refid
+        ),
+        details,
+        ..Default::default()
+    })
+}
+
+// --- SSDP ---
+
+fn ssdp_payloads() -> Vec<Vec<u8>> {
+    vec![
+        b"M-SEARCH * HTTP/1.1\r\nHOST: 239.255.255.250:1900\r\nMAN: \"ssdp:discover\"\r\nMX: 1\r\nST: ssdp:all\r\n\r\n"
+            .to_vec(),
+    ]
+}
+
+fn decode_ssdp(data: &[u8]) -> Option<Fingerprint> {
+    let text = std::str::from_utf8(data).ok()?;
+    if !text.starts_with("HTTP/1.1 200") && !text.starts_with("NOTIFY * HTTP/1.1") {
+        return None;
+    }
+    let mut details = BTreeMap::new();
+    for line in text.lines().skip(1) {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let key = match name.trim().to_ascii_uppercase().as_str() {
+            "SERVER" => "server",
+            "LOCATION" => "location",
+            "ST" | "NT" => "st",
+            "USN" => "usn",
+            _ => continue,
+        };
+        details.insert(key.to_string(), value.trim().to_string());
+    }
+    let banner = details
+        .get("server")
+        .or_else(|| details.get("st"))
+        .cloned()
+        .unwrap_or_default();
+    Some(Fingerprint {
+        service_name: "ssdp".to_string(),
+        banner,
+        details,
+        ..Default::default()
+    })
+}
+
+// --- NetBIOS name service ---
+
+fn netbios_payloads() -> Vec<Vec<u8>> {
+    let mut packet = vec![
+        0x50, 0x44, // Transaction ID
+        0x00, 0x00, // Flags: query
+        0x00, 0x01, // Questions
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // Answer, authority, additional RRs
+        0x20, // Encoded name length
+    ];
+    // The wildcard name "*" padded with NULs, first-level encoded.
+    let mut name = [0u8; 16];
+    name[0] = b'*';
+    for byte in name {
+        packet.push(b'A' + (byte >> 4));
+        packet.push(b'A' + (byte & 0x0F));
+    }
+    packet.extend_from_slice(&[0x00, 0x00, 0x21, 0x00, 0x01]); // NBSTAT, IN
+    vec![packet]
+}
+
+fn decode_netbios(data: &[u8]) -> Option<Fingerprint> {
+    // A positive response: QR bit set and an answer record present.
+    if data.len() < 12 || data[2] & 0x80 == 0 || u16::from_be_bytes([data[6], data[7]]) == 0 {
+        return None;
+    }
+    let mut offset = 12;
+    while *data.get(offset)? != 0 {
+        offset += 1 + data[offset] as usize;
+    }
+    offset += 1;
+    let rr_type = u16::from_be_bytes([*data.get(offset)?, *data.get(offset + 1)?]);
+    if rr_type != 0x0021 {
+        return None;
+    }
+    // Type, class, TTL and RDLENGTH precede the name count.
+    offset += 10;
+    let num_names = *data.get(offset)? as usize;
+    offset += 1;
+
+    let mut names = Vec::new();
+    let mut workstation = None;
+    let mut workgroup = None;
+    for _ in 0..num_names {
+        let entry = data.get(offset..offset + 18)?;
+        offset += 18;
+        let name = String::from_utf8_lossy(&entry[..15]).trim_end().to_string();
+        let suffix = entry[15];
+        let is_group = entry[16] & 0x80 != 0;
+        if suffix == 0x00 && !is_group && workstation.is_none() {
+            workstation = Some(name.clone());
+        }
+        if suffix == 0x00 && is_group && workgroup.is_none() {
+            workgroup = Some(name.clone());
+        }
+        names.push(format!(
+            "{}<{:02X}>{}",
+            name,
+            suffix,
+            if is_group { " (group)" } else { "" }
+        ));
+    }
+
+    let mut details = BTreeMap::new();
+    details.insert("names".to_string(), names.join(", "));
+    if let Some(mac) = data.get(offset..offset + 6) {
+        let mac = mac
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect::<Vec<_>>()
+            .join(":");
+        details.insert("mac".to_string(), mac);
+    }
+    let banner = match (&workstation, &workgroup) {
+        (Some(host), Some(group)) => format!("{} (workgroup {})", host, group),
+        (Some(host), None) => host.clone(),
+        _ => names.first().cloned().unwrap_or_default(),
+    };
+    if let Some(host) = workstation {
+        details.insert("workstation".to_string(), host);
+    }
+    if let Some(group) = workgroup {
+        details.insert("workgroup".to_string(), group);
+    }
+    Some(Fingerprint {
+        service_name: "netbios-ns".to_string(),
+        banner,
+        details,
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snmp_response(varbinds: &[(&[u8], u8, &[u8])]) -> Vec<u8> {
+        let varbinds: Vec<u8> = varbinds
+            .iter()
+            .flat_map(|(oid, tag, value)| {
+                ber_tlv(0x30, &[ber_tlv(0x06, oid), ber_tlv(*tag, value)].concat())
+            })
+            .collect();
+        let pdu = [
+            ber_tlv(0x02, &[0x50, 0x44]),
+            ber_tlv(0x02, &[0x00]),
+            ber_tlv(0x02, &[0x00]),
+            ber_tlv(0x30, &varbinds),
+        ]
+        .concat();
+        let message = [
+            ber_tlv(0x02, &[1]),
+            ber_tlv(0x04, b"public"),
+            ber_tlv(0xA2, &pdu),
+        ]
+        .concat();
+        ber_tlv(0x30, &message)
+    }
+
+    #[test]
+    fn ber_read_short_and_long_lengths() {
+        assert_eq!(
+            ber_read(&[0x04, 0x02, b'h', b'i', 0xFF]),
+            Some((0x04, &b"hi"[..], &[0xFF][..]))
+        );
+        let long = ber_tlv(0x04, &[0xAB; 200]);
+        assert_eq!(&long[..3], &[0x04, 0x81, 200]);
+        let (tag, value, rest) = ber_read(&long).unwrap();
+        assert_eq!((tag, value.len(), rest.len()), (0x04, 200, 0));
+    }
+
+    #[test]
+    fn ber_read_rejects_truncated_values() {
+        assert_eq!(ber_read(&[0x04, 0x05, b'a']), None);
+        assert_eq!(ber_read(&[0x04, 0x82, 0x01]), None);
+        assert_eq!(ber_read(&[0x04, 0x80]), None);
+        assert_eq!(ber_read(&[0x04]), None);
+    }
+
+    #[test]
+    fn snmp_request_round_trips_through_the_reader() {
+        let request = snmp_get_request(1, "public");
+        let (tag, message, rest) = ber_read(&request).unwrap();
+        assert_eq!((tag, rest.len()), (0x30, 0));
+        let (_, version, rest) = ber_read(message).unwrap();
+        let (_, community, rest) = ber_read(rest).unwrap();
+        let (pdu_tag, _, _) = ber_read(rest).unwrap();
+        assert_eq!(
+            (version, community, pdu_tag),
+            (&[1][..], &b"public"[..], 0xA0)
+        );
+    }
+
+    #[test]
+    fn decodes_snmp_system_group() {
+        let reply = snmp_response(&[
+            (SNMP_SYS_DESCR, 0x04, b"Linux router 5.10.0\nsecond line"),
+            (SNMP_SYS_OBJECT_ID, 0x06, &[0x2B, 6, 1, 4, 1, 0x82, 0x37, 1]),
+            (SNMP_SYS_NAME, 0x04, b"router"),
+        ]);
+        let fingerprint = decode_snmp(&reply).unwrap();
+        assert_eq!(fingerprint.service_name, "snmp");
+        assert_eq!(fingerprint.banner, "Linux router 5.10.0");
+        assert_eq!(fingerprint.details["version"], "2c");
+        assert_eq!(fingerprint.details["community"], "public");
+        assert_eq!(fingerprint.details["sysObjectID"], "1.3.6.1.4.1.311.1");
+        assert_eq!(fingerprint.details["sysName"], "router");
+    }
+
+    #[test]
+    fn snmp_without_sys_descr_falls_back_to_version_banner() {
+        // noSuchObject for sysDescr.
+        let reply = snmp_response(&[(SNMP_SYS_DESCR, 0x80, &[])]);
+        let fingerprint = decode_snmp(&reply).unwrap();
+        assert_eq!(fingerprint.banner, "SNMPv2c (community: public)");
+        assert!(!fingerprint.details.contains_key("sysDescr"));
+    }
+
+    #[test]
+    fn snmp_rejects_requests_and_garbage() {
+        assert!(decode_snmp(&snmp_get_request(1, "public")).is_none());
+        assert!(decode_snmp(b"HTTP/1.1 400 Bad Request\r\n").is_none());
+    }
+
+    #[test]
+    fn decodes_ntp_server_reply() {
+        let mut reply = vec![0; 48];
+        reply[0] = 0x24; // LI=0, VN=4, Mode=4
+        reply[1] = 2;
+        reply[12..16].copy_from_slice(&[192, 168, 1, 1]);
+        let fingerprint = decode_ntp(&reply).unwrap();
+        assert_eq!(
+            fingerprint.banner,
+            "NTPv4 server, stratum 2, refid 192.168.1.1"
+        );
+
+        reply[1] = 1;
+        reply[12..16].copy_from_slice(b"GPS\0");
+        assert_eq!(decode_ntp(&reply).unwrap().details["refid"], "GPS");
+    }
+
+    #[test]
+    fn ntp_rejects_client_packets_and_short_replies() {
+        assert!(decode_ntp(&ntp_payloads()[0]).is_none());
+        let mut reply = vec![0; 47];
+        reply[0] = 0x24;
+        assert!(decode_ntp(&reply).is_none());
+    }
+
+    #[test]
+    fn decodes_ssdp_headers_case_insensitively() {
+        let reply = b"HTTP/1.1 200 OK\r\nCache-Control: max-age=1800\r\nserver: Linux/5.4 UPnP/1.0 MiniUPnPd/2.2\r\nLOCATION: http://10.0.0.1:5000/rootDesc.xml\r\nST: upnp:rootdevice\r\n\r\n";
+        let fingerprint = decode_ssdp(reply).unwrap();
+        assert_eq!(fingerprint.banner, "Linux/5.4 UPnP/1.0 MiniUPnPd/2.2");
+        assert_eq!(
+            fingerprint.details["location"],
+            "http://10.0.0.1:5000/rootDesc.xml"
+        );
+        assert_eq!(fingerprint.details["st"], "upnp:rootdevice");
+        assert!(decode_ssdp(b"HTTP/1.1 404 Not Found\r\n\r\n").is_none());
+    }
+
+    fn netbios_response(entries: &[(&str, u8, u16)]) -> Vec<u8> {
+        let mut reply = vec![0x50, 0x44, 0x84, 0x00, 0x00, 0x00, 0x00, 0x01, 0, 0, 0, 0];
+        reply.push(0x20);
+        reply.extend_from_slice(b"CK");
+        reply.extend_from_slice(&[b'A'; 30]);
+        reply.push(0x00);
+        reply.extend_from_slice(&[0x00, 0x21, 0x00, 0x01, 0, 0, 0, 0]);
+        let rdlength = 1 + entries.len() * 18 + 6;
+        reply.extend_from_slice(&(rdlength as u16).to_be_bytes());
+        reply.push(entries.len() as u8);
+        for (name, suffix, flags) in entries {
+            reply.extend_from_slice(format!("{:<15}", name).as_bytes());
+            reply.push(*suffix);
+            reply.extend_from_slice(&flags.to_be_bytes());
+        }
+        reply.extend_from_slice(&[0x00, 0x11, 0x22, 0x33, 0x44, 0x55]);
+        reply
+    }
+
+    #[test]
+    fn decodes_netbios_node_status() {
+        let reply = netbios_response(&[
+            ("FILESERVER", 0x00, 0x0400),
+            ("WORKGROUP", 0x00, 0x8400),
+            ("FILESERVER", 0x20, 0x0400),
+        ]);
+        let fingerprint = decode_netbios(&reply).unwrap();
+        assert_eq!(fingerprint.service_name, "netbios-ns");
+        assert_eq!(fingerprint.banner, "FILESERVER (workgroup WORKGROUP)");
+        assert_eq!(
+            fingerprint.details["names"],
+            "FILESERVER<00>, WORKGROUP<00> (group), FILESERVER<20>"
+        );
+        assert_eq!(fingerprint.details["mac"], "00:11:22:33:44:55");
+    }
+
+    #[test]
+    fn netbios_rejects_queries_and_truncated_names() {
+        assert!(decode_netbios(&netbios_payloads()[0]).is_none());
+        let mut reply = netbios_response(&[("FILESERVER", 0x00, 0x0400)]);
+        reply.truncate(reply.len() - 10);
+        assert!(decode_netbios(&reply).is_none());
+    }
+}
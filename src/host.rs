@@ -0,0 +1,435 @@
+//! Per-host conclusions drawn from every service fingerprint gathered for that host.
+//!
+//! Banners often name the OS outright (`OpenSSH_8.9p1 Ubuntu-3ubuntu0.4`,
+//! `Microsoft-IIS/10.0`) or reveal the kind of device behind them. Each hint carries a
+//! weight; agreeing hints reinforce each other and conflicting ones lower confidence.
+
+use crate::fingerprint::Fingerprint;
+use crate::os::OsDetection;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// Device type assumed for hosts running a general-purpose OS with no other indication.
+const GENERAL_PURPOSE: &str = "general purpose";
+
+/// Scale applied to `-O` accuracy, so stack matches never outweigh an explicit banner.
+const STACK_WEIGHT: f32 = 0.8;
+
+/// Open ports a host needs before it can look like a tarpit; fewer is ordinary.
+const TARPIT_MIN_OPEN: usize = 20;
+/// Share of scanned ports accepting connections beyond which no real host is that busy.
+const TARPIT_OPEN_SHARE: f64 = 0.5;
+/// Share of open ports never sending data beyond which the accepts are not real services.
+const TARPIT_SILENT_SHARE: f64 = 0.9;
+
+/// What a banner or confirmed service says about the host.
+#[derive(Debug, Clone)]
+pub struct Hint {
+    pub family: Option<String>,
+    pub distribution: Option<&'static str>,
+    pub device_type: Option<&'static str>,
+    /// How strongly the hint alone suggests its conclusion, from 0 to 1.
+    pub weight: f32,
+    pub evidence: String,
+}
+
+struct Rule {
+    regex: Regex,
+    family: Option<&'static str>,
+    distribution: Option<&'static str>,
+    device_type: Option<&'static str>,
+    weight: f32,
+}
+
+/// Patterns applied to banners and decoded detail values.
+static BANNER_RULES: Lazy<Vec<Rule>> = Lazy::new(|| {
+    let linux = Some("Linux");
+    [
+        (r"(?i)ubuntu", linux, Some("Ubuntu"), None, 0.9),
+        (r"(?i)debian|deb\d+u\d+", linux, Some("Debian"), None, 0.9),
+        (r"(?i)centos", linux, Some("CentOS"), None, 0.9),
+        (
+            r"(?i)red ?hat|\.el[6-9]\b",
+            linux,
+            Some("Red Hat"),
+            None,
+            0.85,
+        ),
+        (r"(?i)fedora", linux, Some("Fedora"), None, 0.85),
+        (r"(?i)alpine", linux, Some("Alpine"), None, 0.8),
+        (r"(?i)raspbian", linux, Some("Raspbian"), None, 0.85),
+        (
+            r"(?i)openwrt|dd-wrt",
+            linux,
+            Some("OpenWrt"),
+            Some("router"),
+            0.85,
+        ),
+        (r"(?i)synology|qnap", linux, None, Some("storage"), 0.8),
+        (r"(?i)busybox", linux, None, Some("embedded"), 0.7),
+        (r"(?i)\blinux\b", linux, None, None, 0.6),
+        (
+            r"(?i)microsoft-(iis|httpapi)",
+            Some("Windows"),
+            None,
+            None,
+            0.9,
+        ),
+        (
+            r"(?i)microsoft|windows|win(32|64)\b",
+            Some("Windows"),
+            None,
+            None,
+            0.7,
+        ),
+        (r"(?i)freebsd", Some("FreeBSD"), None, None, 0.85),
+        (r"(?i)openbsd", Some("OpenBSD"), None, None, 0.85),
+        (r"(?i)darwin|mac ?os", Some("macOS"), None, None, 0.7),
+        (
+            r"(?i)vmware esxi",
+            Some("VMware ESXi"),
+            None,
+            Some("hypervisor"),
+            0.9,
+        ),
+        (r"(?i)cisco", Some("IOS"), None, Some("router"), 0.8),
+        (
+            r"(?i)routeros|mikrotik",
+            Some("RouterOS"),
+            None,
+            Some("router"),
+            0.9,
+        ),
+        (
+            r"(?i)junos|juniper",
+            Some("Junos"),
+            None,
+            Some("router"),
+            0.85,
+        ),
+        (
+            r"(?i)jetdirect|laserjet|printer",
+            None,
+            None,
+            Some("printer"),
+            0.8,
+        ),
+        (
+            r"(?i)hikvision|ip ?camera|webcam",
+            None,
+            None,
+            Some("webcam"),
+            0.7,
+        ),
+    ]
+    .into_iter()
+    .map(
+        |(pattern, family, distribution, device_type, weight)| Rule {
+            regex: Regex::new(pattern).unwrap(),
+            family,
+            distribution,
+            device_type,
+            weight,
+        },
+    )
+    .collect()
+});
+
+/// Services whose mere presence (once confirmed) points at an OS or device type.
+const SERVICE_RULES: &[(&str, Option<&str>, Option<&str>, f32)] = &[
+    ("ms-wbt-server", Some("Windows"), None, 0.5),
+    ("ms-sql-s", Some("Windows"), None, 0.5),
+    ("modbus", None, Some("PLC"), 0.9),
+    ("s7comm", None, Some("PLC"), 0.9),
+    ("enip", None, Some("PLC"), 0.9),
+    ("dnp3", None, Some("PLC"), 0.9),
+    ("bacnet", None, Some("building automation controller"), 0.9),
+];
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HostOs {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub family: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub distribution: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_type: Option<String>,
+    /// Percentage confidence in `family`, or in `device_type` when no family is known.
+    pub confidence: u8,
+    pub evidence: Vec<String>,
+}
+
+/// Signs that open ports are accepted by a tarpit, honeypot or proxy rather than real services.
+#[derive(Debug, Clone, Serialize)]
+pub struct Tarpit {
+    /// "most-ports-open" or "silent-accepts".
+    pub reason: &'static str,
+    pub open_ports: usize,
+    pub scanned_ports: usize,
+    /// Open ports that never sent any data.
+    pub silent_ports: usize,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct HostSummary {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub os: Option<HostOs>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tarpit: Option<Tarpit>,
+}
+
+/// Flags a host where an implausible share of the `scanned` ports accepted connections,
+/// or where nearly every accepted connection stayed silent.
+pub fn detect_tarpit(fingerprints: &[(u16, Fingerprint)], scanned: usize) -> Option<Tarpit> {
+    let open_ports = fingerprints.len();
+    if open_ports < TARPIT_MIN_OPEN {
+        return None;
+    }
+    let silent_ports = fingerprints.iter().filter(|(_, f)| f.silent).count();
+    let reason = if open_ports as f64 >= scanned as f64 * TARPIT_OPEN_SHARE {
+        "most-ports-open"
+    } else if silent_ports as f64 >= open_ports as f64 * TARPIT_SILENT_SHARE {
+        "silent-accepts"
+    } else {
+        return None;
+    };
+    Some(Tarpit {
+        reason,
+        open_ports,
+        scanned_ports: scanned,
+        silent_ports,
+    })
+}
+
+/// Collects every OS and device hint from banners, details and confirmed services.
+pub fn hints(fingerprints: &[(u16, Fingerprint)]) -> Vec<Hint> {
+    let mut hints: Vec<Hint> = Vec::new();
+    for (port, fingerprint) in fingerprints {
+        let source = format!("{}/{}", fingerprint.service_name, port);
+        let texts = std::iter::once(&fingerprint.banner).chain(fingerprint.details.values());
+        for text in texts {
+            let first_for_text = hints.len();
+            for rule in BANNER_RULES.iter() {
+                // Rules run most specific first; one text counts once per family.
+                if rule.family.is_some()
+                    && hints[first_for_text..]
+                        .iter()
+                        .any(|h| h.family.as_deref() == rule.family)
+                {
+                    continue;
+                }
+                if let Some(found) = rule.regex.find(text) {
+                    hints.push(Hint {
+                        family: rule.family.map(str::to_string),
+                        distribution: rule.distribution,
+                        device_type: rule.device_type,
+                        weight: rule.weight,
+                        evidence: format!("{}: \"{}\"", source, found.as_str()),
+                    });
+                }
+            }
+        }
+        if fingerprint.guessed {
+            continue;
+        }
+        for &(service, family, device_type, weight) in SERVICE_RULES {
+            if fingerprint.service_name == service {
+                hints.push(Hint {
+                    family: family.map(str::to_string),
+                    distribution: None,
+                    device_type,
+                    weight,
+                    evidence: format!("{} service", source),
+                });
+            }
+        }
+    }
+    hints
+}
+
+/// Combines banner hints and, when `-O` ran, the best stack match into one conclusion.
+pub fn summarize(
+    fingerprints: &[(u16, Fingerprint)],
+    os_detection: Option<&OsDetection>,
+) -> HostSummary {
+    let mut hints = hints(fingerprints);
+    if let Some(best) = os_detection.and_then(|d| d.matches.first()) {
+        hints.push(Hint {
+            family: Some(best.family.clone()),
+            distribution: None,
+            device_type: None,
+            weight: f32::from(best.accuracy) / 100.0 * STACK_WEIGHT,
+            evidence: format!("TCP/IP stack: {}", best.name),
+        });
+    }
+
+    if hints.is_empty() {
+        return HostSummary::default();
+    }
+
+    // Independent hints for the same family combine as 1 - Π(1 - w).
+    let mut doubt: BTreeMap<&str, f32> = BTreeMap::new();
+    for hint in &hints {
+        if let Some(family) = &hint.family {
+            *doubt.entry(family).or_insert(1.0) *= 1.0 - hint.weight;
+        }
+    }
+    let scores: Vec<(&str, f32)> = doubt.into_iter().map(|(f, d)| (f, 1.0 - d)).collect();
+    let total: f32 = scores.iter().map(|(_, s)| s).sum();
+    let best = scores.iter().max_by(|a, b| a.1.total_cmp(&b.1)).copied();
+    let family = best.map(|(family, _)| family);
+
+    let agreeing = |hint: &&Hint| hint.family.is_none() || hint.family.as_deref() == family;
+    let distribution = strongest(hints.iter().filter(agreeing), |h| h.distribution);
+    let device_type = strongest(hints.iter().filter(agreeing), |h| h.device_type).or_else(|| {
+        matches!(
+            family,
+            Some("Linux" | "Windows" | "FreeBSD" | "OpenBSD" | "macOS" | "Solaris")
+        )
+        .then_some(GENERAL_PURPOSE)
+    });
+    // Conflicting families dilute the winner; device-only hosts use their strongest hint.
+    let confidence = match best {
+        Some((_, score)) => score * score / total,
+        None => hints.iter().map(|h| h.weight).fold(0.0, f32::max),
+    };
+    let mut evidence: Vec<String> = Vec::new();
+    for hint in hints.iter().filter(agreeing) {
+        if !evidence.contains(&hint.evidence) {
+            evidence.push(hint.evidence.clone());
+        }
+    }
+
+    HostSummary {
+        os: Some(HostOs {
+            family: family.map(str::to_string),
+            distribution: distribution.map(str::to_string),
+            device_type: device_type.map(str::to_string),
+            confidence: (confidence * 100.0).round() as u8,
+            evidence,
+        }),
+        tarpit: None,
+    }
+}
+
+/// The value of `field` from the highest-weighted hint that has one.
+fn strongest<'a>(
+    hints: impl Iterator<Item = &'a Hint>,
+    field: impl Fn(&Hint) -> Option<&'static str>,
+) -> Option<&'static str> {
+    hints
+        .filter_map(|h| field(h).map(|value| (value, h.weight)))
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .map(|(value, _)| value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::os::OsMatch;
+
+    fn service(port: u16, name: &str, banner: &str) -> (u16, Fingerprint) {
+        let fingerprint = Fingerprint {
+            service_name: name.to_string(),
+            banner: banner.to_string(),
+            ..Default::default()
+        };
+        (port, fingerprint)
+    }
+
+    #[test]
+    fn banner_names_the_distribution() {
+        let summary = summarize(
+            &[service(22, "ssh", "OpenSSH_8.9p1 Ubuntu-3ubuntu0.4")],
+            None,
+        );
+        let os = summary.os.unwrap();
+        assert_eq!(os.family.as_deref(), Some("Linux"));
+        assert_eq!(os.distribution.as_deref(), Some("Ubuntu"));
+        assert_eq!(os.device_type.as_deref(), Some(GENERAL_PURPOSE));
+        assert_eq!(os.confidence, 90);
+        assert_eq!(os.evidence, ["ssh/22: \"Ubuntu\""]);
+    }
+
+    #[test]
+    fn conflicting_hints_lower_confidence() {
+        let fingerprints = [
+            service(22, "ssh", "OpenSSH_8.9p1 Ubuntu-3ubuntu0.4"),
+            service(3389, "ms-wbt-server", "[Binary data: 19 bytes]"),
+        ];
+        let os = summarize(&fingerprints, None).os.unwrap();
+        assert_eq!(os.family.as_deref(), Some("Linux"));
+        // 0.9² / (0.9 + 0.5)
+        assert_eq!(os.confidence, 58);
+        assert_eq!(os.evidence, ["ssh/22: \"Ubuntu\""]);
+    }
+
+    #[test]
+    fn guessed_services_are_not_evidence() {
+        let (port, mut fingerprint) = service(3389, "ms-wbt-server", "");
+        fingerprint.guessed = true;
+        assert!(hints(&[(port, fingerprint)]).is_empty());
+    }
+
+    #[test]
+    fn stack_match_reinforces_banners() {
+        let detection = OsDetection {
+            stack: None,
+            matches: vec![OsMatch {
+                name: "Linux 4.x - 6.x".to_string(),
+                family: "Linux".to_string(),
+                accuracy: 100,
+            }],
+        };
+        let fingerprints = [service(22, "ssh", "OpenSSH_8.9p1 Ubuntu-3ubuntu0.4")];
+        let os = summarize(&fingerprints, Some(&detection)).os.unwrap();
+        // 1 - (1 - 0.9) × (1 - 0.8)
+        assert_eq!(os.confidence, 98);
+        assert!(
+            os.evidence
+                .contains(&"TCP/IP stack: Linux 4.x - 6.x".to_string())
+        );
+    }
+
+    #[test]
+    fn device_only_hints_use_the_strongest() {
+        let os = summarize(&[service(9100, "jetdirect", "HP LaserJet M404")], None)
+            .os
+            .unwrap();
+        assert_eq!(os.family, None);
+        assert_eq!(os.device_type.as_deref(), Some("printer"));
+        assert_eq!(os.confidence, 80);
+    }
+
+    fn open_ports(count: u16, silent: u16) -> Vec<(u16, Fingerprint)> {
+        (1..=count)
+            .map(|port| {
+                let (port, mut fingerprint) = service(port, "unknown", "");
+                fingerprint.silent = port <= silent;
+                (port, fingerprint)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn few_open_ports_are_never_a_tarpit() {
+        assert!(detect_tarpit(&open_ports(19, 19), 19).is_none());
+    }
+
+    #[test]
+    fn flags_hosts_with_most_ports_open() {
+        let tarpit = detect_tarpit(&open_ports(600, 0), 1000).unwrap();
+        assert_eq!(tarpit.reason, "most-ports-open");
+        assert_eq!((tarpit.open_ports, tarpit.scanned_ports), (600, 1000));
+    }
+
+    #[test]
+    fn flags_hosts_whose_accepts_stay_silent() {
+        let tarpit = detect_tarpit(&open_ports(100, 95), 1000).unwrap();
+        assert_eq!(tarpit.reason, "silent-accepts");
+        assert_eq!(tarpit.silent_ports, 95);
+        assert!(detect_tarpit(&open_ports(100, 80), 1000).is_none());
+    }
+}
@@ -1,17 +1,31 @@
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
 use colored::Colorize;
 use futures::stream::{self, FuturesUnordered, StreamExt};
-use indicatif::{ProgressBar, ProgressStyle};
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
 use serde::Serialize;
+use std::collections::BTreeMap;
 use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
 use std::process;
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
-use tokio::net::TcpStream;
-use tokio::sync::Semaphore;
+use tokio::sync::{Semaphore, watch};
 use tokio::time::timeout;
 
+mod checkpoint;
+mod congestion;
+mod controls;
+mod discovery;
 mod fingerprint;
+mod host;
+mod os;
+mod pacing;
+mod raw;
+mod rawscan;
+mod services;
+mod shutdown;
+mod source;
+mod targets;
 
 const ASCII_ART: &str = r#"
  ____            _     ____              
@@ -25,43 +39,318 @@ A lightning-fast port scanner built with Rust.
 
 #[derive(Serialize)]
 struct ScanReport {
+    /// Set when the scan was stopped before finishing.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    incomplete: bool,
+    /// Share of the work done, for an incomplete scan.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    completed_percent: Option<f64>,
+    /// Effective timing; absent when no host was up to scan.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    settings: Option<SettingsReport>,
+    hosts: Vec<HostReport>,
+}
+
+/// The timing template with every override applied.
+#[derive(Serialize)]
+struct SettingsReport {
+    timing: u8,
+    concurrency: usize,
+    connect_timeout_ms: u128,
+    /// Absent when each probe waits for as long as it defines.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    banner_timeout_ms: Option<u128>,
+    read_size: usize,
+    max_retries: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    host_timeout_ms: Option<u128>,
+    /// Hosts scanned at once, sharing `concurrency` equally.
+    parallel_hosts: usize,
+}
+
+#[derive(Serialize)]
+struct HostReport {
     target: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hostname: Option<String>,
+    state: discovery::HostState,
+    /// What discovery saw: "syn-ack", "reset", "echo-reply", "arp-cache", "user-set" or "no-response".
+    reason: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    latency_ms: Option<f64>,
+    #[serde(flatten)]
+    summary: host::HostSummary,
     open_ports: Vec<PortReport>,
+    /// Reported ports in states other than open (filtered, unfiltered, open|filtered).
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    other_ports: Vec<PortReport>,
+    /// Ports left out of the report, counted by state.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    not_shown: BTreeMap<&'static str, usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    os_detection: Option<os::OsDetection>,
+    #[serde(skip_serializing_if = "RetryCounts::is_empty")]
+    retries: RetryCounts,
+    /// The scan was abandoned at `--host-timeout`; no ports are reported.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    timed_out: bool,
+    /// The scan was stopped early; the ports reported are those found until then.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    incomplete: bool,
 }
 
 #[derive(Serialize)]
 struct PortReport {
     port: u16,
-    state: &'static str,
+    protocol: &'static str,
+    state: rawscan::PortState,
     service: String,
+    /// True when `service` was inferred from the port number rather than the reply.
+    service_guessed: bool,
     banner: String,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    details: BTreeMap<String, String>,
 }
 
 /// A lightning-fast asynchronous port scanner with adaptive timing and fingerprinting.
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
-    /// The IP address to scan.
-    #[arg(required = true)]
-    ipaddr: IpAddr,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Targets to scan: IP addresses, CIDR ranges (10.0.0.0/24) or hostnames.
+    #[arg(required_unless_present = "resume", value_name = "TARGET")]
+    targets: Vec<String>,
 
     /// Ports to scan. Ex: 80,443 | 1-1024 | -
     #[arg(short, long, default_value = "1-1024")]
     ports: String,
 
+    /// Scan the N most commonly open ports instead of --ports.
+    #[arg(long, value_name = "N", conflicts_with = "ports")]
+    top_ports: Option<usize>,
+
+    /// Skip host discovery and scan every target as if it were up (-Pn).
+    #[arg(short = 'P', value_enum, value_name = "MODE")]
+    ping: Option<PingMode>,
+
+    /// TCP ports connected to during host discovery; any answer, even a reset, marks the host up.
+    #[arg(long, value_name = "PORTS", default_value = "80,443,22,53,3389,8080")]
+    ping_ports: String,
+
     /// Set timing template (0-5, default: 3). Higher is faster and more aggressive.
     #[arg(short = 'T', long, default_value_t = 3, value_parser = clap::value_parser!(u8).range(0..=5))]
     timing: u8,
 
+    /// Retries for a timed-out connect, made only once the host has shown packet loss.
+    /// Defaults to 5 at -T0/-T1, 4 at -T2, 3 at -T3, 2 at -T4 and 1 at -T5.
+    #[arg(long, value_name = "N")]
+    max_retries: Option<u8>,
+
+    /// Connects kept in flight at once, overriding the timing template.
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    concurrency: Option<u32>,
+
+    /// Initial connect timeout, overriding the timing template. Ex: 500ms | 2s
+    #[arg(long, value_name = "TIME", value_parser = parse_positive_duration)]
+    connect_timeout: Option<Duration>,
+
+    /// How long to wait for each probe's reply, instead of the probe's own wait.
+    #[arg(long, value_name = "TIME", value_parser = parse_positive_duration)]
+    banner_timeout: Option<Duration>,
+
+    /// Give up on a host whose scan takes longer than this and report it as timed out.
+    #[arg(long, value_name = "TIME", value_parser = parse_positive_duration)]
+    host_timeout: Option<Duration>,
+
+    /// Scan at most N hosts at once; wins over --min-parallel-hosts.
+    #[arg(long, value_name = "N", default_value_t = 64, value_parser = clap::value_parser!(u32).range(1..))]
+    max_hostgroup: u32,
+
+    /// Scan at least N hosts at once when that many are up, so one slow host cannot hold up
+    /// the rest. Each host gets an equal share of the concurrency.
+    #[arg(long, value_name = "N", default_value_t = 4, value_parser = clap::value_parser!(u32).range(1..))]
+    min_parallel_hosts: u32,
+
+    /// Send at most N probes per second, across all targets.
+    #[arg(long, value_name = "N", value_parser = parse_rate)]
+    max_rate: Option<f64>,
+
+    /// Send at least N probes per second, even when congestion control would hold back.
+    #[arg(long, value_name = "N", value_parser = parse_rate)]
+    min_rate: Option<f64>,
+
+    /// Wait at least this long between probes to the same host. Ex: 500ms | 2s | 1m
+    #[arg(long, value_name = "TIME", value_parser = parse_duration)]
+    scan_delay: Option<Duration>,
+
+    /// Let a host's scan delay double up to this long while it drops probes.
+    #[arg(long, value_name = "TIME", value_parser = parse_duration)]
+    max_scan_delay: Option<Duration>,
+
+    /// Send every probe from this local address, for multi-homed hosts.
+    #[arg(long, value_name = "ADDR")]
+    source_ip: Option<IpAddr>,
+
+    /// Send every probe through this network interface (Linux only).
+    #[arg(long, short = 'e', value_name = "NAME")]
+    interface: Option<String>,
+
+    /// Send every probe from this local port.
+    #[arg(long, short = 'g', value_name = "PORT")]
+    source_port: Option<u16>,
+
     /// Output results in JSON format, suppressing all other output.
     #[arg(long, short)]
     json: bool,
+
+    /// Scan UDP ports instead of TCP, decoding replies from known UDP services.
+    #[arg(long, short = 'U')]
+    udp: bool,
+
+    /// Scan technique. All but T send raw packets and need Linux and root or CAP_NET_RAW;
+    /// without them S falls back to a connect scan and the others stop with an error.
+    #[arg(short = 's', long, value_enum, value_name = "TYPE", default_value_t = ScanType::Connect)]
+    scan_type: ScanType,
+
+    /// Probe intensity (0-9). Higher values also send rarer probes to ports they are not registered for.
+    #[arg(long, default_value_t = fingerprint::DEFAULT_VERSION_INTENSITY, value_parser = clap::value_parser!(u8).range(0..=9))]
+    version_intensity: u8,
+
+    /// Maximum number of bytes collected from a single service reply.
+    #[arg(long, default_value_t = fingerprint::DEFAULT_READ_SIZE, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    read_size: usize,
+
+    /// Silence after which a partially received reply is considered complete. Ex: 300ms | 1s
+    #[arg(long, value_name = "TIME", default_value = "300ms", value_parser = parse_positive_duration)]
+    read_idle_gap: Duration,
+
+    /// Load extra probes and matchers from a TOML file, merged with the built-ins.
+    #[arg(long, value_name = "FILE")]
+    probes_file: Option<PathBuf>,
+
+    /// Load port-to-service names in nmap-services format, overriding the built-in table.
+    #[arg(long, value_name = "FILE")]
+    services_file: Option<PathBuf>,
+
+    /// Identify ICS devices (Modbus, S7, EtherNet/IP, BACnet, DNP3) with read-only probes.
+    /// ICS ports are always scanned one at a time with polite timing.
+    #[arg(long)]
+    ics: bool,
+
+    /// Guess the target OS from how its TCP/IP stack answers crafted probes (needs root or CAP_NET_RAW).
+    #[arg(short = 'O', long = "os")]
+    os_detection: bool,
+
+    /// Load extra OS signatures from a TOML file, consulted alongside the built-ins.
+    #[arg(long, value_name = "FILE", requires = "os_detection")]
+    os_db: Option<PathBuf>,
+
+    /// On hosts that look like tarpits or proxies, leave out open ports that never sent data
+    /// and just count them.
+    #[arg(long)]
+    hide_tarpit_ports: bool,
+
+    /// Save progress to FILE every 30 seconds and when the scan ends, for --resume.
+    #[arg(long, value_name = "FILE")]
+    checkpoint: Option<PathBuf>,
+
+    /// Continue the scan saved in a checkpoint FILE with its original options, skipping
+    /// the work already done.
+    #[arg(long, value_name = "FILE", exclusive = true)]
+    resume: Option<PathBuf>,
+}
+
+/// Minimum connect timeout used for ICS ports regardless of the `-T` template.
+const ICS_POLITE_TIMEOUT: Duration = Duration::from_millis(1200);
+/// How long host discovery waits for any ping to be answered.
+const PING_TIMEOUT: Duration = Duration::from_secs(2);
+/// Pause after each ICS probe so fragile controllers are never hit back to back.
+const ICS_SCAN_DELAY: Duration = Duration::from_millis(400);
+/// Longest pause before retrying a timed-out connect, however slow the template.
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(10);
+/// Highest concurrency the `+` key can reach, file descriptors permitting.
+const MAX_LIVE_CONCURRENCY: usize = 20_000;
+/// How often a paused raw scan checks whether it may send again.
+const RAW_PAUSE_POLL: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum ScanType {
+    /// Full TCP connect (-sT)
+    #[value(name = "T", alias = "connect")]
+    Connect,
+    /// Half-open SYN scan; only open ports get a full connection (-sS)
+    #[value(name = "S", alias = "syn")]
+    Syn,
+    /// ACK scan mapping firewall rules: unfiltered or filtered (-sA)
+    #[value(name = "A", alias = "ack")]
+    Ack,
+    /// FIN scan: closed ports answer RST, others are open|filtered (-sF)
+    #[value(name = "F", alias = "fin")]
+    Fin,
+    /// NULL scan, no flags set (-sN)
+    #[value(name = "N", alias = "null")]
+    Null,
+    /// Xmas scan, FIN+PSH+URG (-sX)
+    #[value(name = "X", alias = "xmas")]
+    Xmas,
+    /// ACK scan that reads the RST window size to tell open from closed (-sW)
+    #[value(name = "W", alias = "window")]
+    Window,
+}
+
+impl ScanType {
+    fn technique(self) -> Option<rawscan::Technique> {
+        match self {
+            ScanType::Connect => None,
+            ScanType::Syn => Some(rawscan::Technique::Syn),
+            ScanType::Ack => Some(rawscan::Technique::Ack),
+            ScanType::Fin => Some(rawscan::Technique::Fin),
+            ScanType::Null => Some(rawscan::Technique::Null),
+            ScanType::Xmas => Some(rawscan::Technique::Xmas),
+            ScanType::Window => Some(rawscan::Technique::Window),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum PingMode {
+    /// No discovery; every target is treated as up (-Pn)
+    #[value(name = "n", alias = "none")]
+    Skip,
+}
+
+/// A non-open state is summarised instead of listed once more ports than this share it.
+const MAX_LISTED_PER_STATE: usize = 25;
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Work with user-defined probe and matcher files.
+    Probes {
+        #[command(subcommand)]
+        action: ProbesCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ProbesCommand {
+    /// Validate a probes file and report every problem found.
+    Lint {
+        /// The TOML file to check.
+        file: PathBuf,
+    },
 }
 
 #[derive(Clone)]
 struct ScanSettings {
     concurrency: usize,
     timeout: Duration,
+    /// Retries for a timed-out connect once the host has shown packet loss.
+    max_retries: u8,
+    /// Give up on a host once its scan has run this long.
+    host_timeout: Option<Duration>,
 }
 
 async fn determine_optimal_settings(ip: IpAddr) -> ScanSettings {
@@ -77,13 +366,13 @@ async fn determine_optimal_settings(ip: IpAddr) -> ScanSettings {
         let probe_task = tokio::spawn(async move {
             let start = Instant::now();
             let socket_addr = SocketAddr::new(ip, port);
-            if timeout(Duration::from_secs(2), TcpStream::connect(&socket_addr))
-                .await
-                .is_ok()
-            {
-                return Some(start.elapsed());
+            match timeout(Duration::from_secs(2), source::connect_tcp(socket_addr)).await {
+                Ok(Ok(_)) => Some(start.elapsed()),
+                Ok(Err(e)) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+                    Some(start.elapsed())
+                }
+                _ => None,
             }
-            None
         });
         probe_tasks.push(probe_task);
     }
@@ -103,6 +392,8 @@ async fn determine_optimal_settings(ip: IpAddr) -> ScanSettings {
         return ScanSettings {
             concurrency: 500,
             timeout: Duration::from_millis(3000),
+            max_retries: 2,
+            host_timeout: None,
         };
     }
 
@@ -118,29 +409,13 @@ async fn determine_optimal_settings(ip: IpAddr) -> ScanSettings {
         1000
     };
 
-    #[cfg(unix)]
-    let concurrency = {
-        let mut concurrency = concurrency;
-        if let Ok((soft_limit, _)) = rlimit::getrlimit(rlimit::Resource::NOFILE) {
-            let safe_limit = soft_limit.saturating_sub(50) as usize;
-            if concurrency > safe_limit {
-                println!(
-                    "{}",
-                    format!(
-                        "Warning: Capping concurrency at {} to respect file descriptor limit.",
-                        safe_limit
-                    )
-                    .yellow()
-                );
-                concurrency = safe_limit;
-            }
-        }
-        concurrency
-    };
+    let concurrency = cap_to_fd_limit(concurrency);
 
     let settings = ScanSettings {
         concurrency,
         timeout,
+        max_retries: 2,
+        host_timeout: None,
     };
     println!(
         "{}{}{}{}{}",
@@ -157,6 +432,131 @@ async fn determine_optimal_settings(ip: IpAddr) -> ScanSettings {
     settings
 }
 
+/// Sockets that may be open at once, leaving some file descriptors spare.
+fn fd_budget() -> Option<usize> {
+    #[cfg(unix)]
+    if let Ok((soft_limit, _)) = rlimit::getrlimit(rlimit::Resource::NOFILE) {
+        return Some(soft_limit.saturating_sub(50) as usize);
+    }
+    None
+}
+
+/// Lowers `concurrency` to what the open file descriptor limit allows.
+fn cap_to_fd_limit(concurrency: usize) -> usize {
+    if let Some(safe_limit) = fd_budget()
+        && concurrency > safe_limit
+    {
+        println!(
+            "{}",
+            format!(
+                "Warning: Capping concurrency at {} to respect file descriptor limit.",
+                safe_limit
+            )
+            .yellow()
+        );
+        return safe_limit;
+    }
+    concurrency
+}
+
+/// Layers the command-line timing overrides on top of the template's settings.
+fn apply_overrides(settings: &mut ScanSettings, args: &Args) {
+    if let Some(concurrency) = args.concurrency {
+        settings.concurrency = cap_to_fd_limit(concurrency as usize);
+    }
+    if let Some(timeout) = args.connect_timeout {
+        settings.timeout = timeout;
+    }
+    if let Some(max_retries) = args.max_retries {
+        settings.max_retries = max_retries;
+    }
+    settings.host_timeout = args.host_timeout;
+}
+
+/// How many of the `up_count` live hosts to scan at once: enough that together they can use
+/// the whole concurrency budget, within `--min-parallel-hosts` and `--max-hostgroup`.
+fn parallel_hosts(settings: &ScanSettings, ports: usize, up_count: usize, args: &Args) -> usize {
+    let to_fill_budget = settings.concurrency.div_ceil(ports.max(1));
+    to_fill_budget
+        .max(args.min_parallel_hosts as usize)
+        .min(args.max_hostgroup as usize)
+        .min(up_count)
+        .max(1)
+}
+
+/// Settings for timing template `timing`; `-T4` measures them against `probe_target`.
+/// `pacing` describes any rate limits, shown alongside the template in the banner.
+async fn timing_settings(
+    timing: u8,
+    json: bool,
+    probe_target: IpAddr,
+    pacing: Option<String>,
+) -> ScanSettings {
+    let (profile, settings) = match timing {
+        5 => (
+            "Insane (-T5)".red(),
+            Some(ScanSettings {
+                concurrency: 5000,
+                timeout: Duration::from_millis(300),
+                max_retries: 1,
+                host_timeout: None,
+            }),
+        ),
+        4 => ("Aggressive (-T4, auto)".yellow(), None),
+        2 => (
+            "Polite (-T2)".blue(),
+            Some(ScanSettings {
+                concurrency: 400,
+                timeout: Duration::from_millis(1200),
+                max_retries: 4,
+                host_timeout: None,
+            }),
+        ),
+        1 => (
+            "Sneaky (-T1)".dimmed(),
+            Some(ScanSettings {
+                concurrency: 100,
+                timeout: Duration::from_secs(5),
+                max_retries: 5,
+                host_timeout: None,
+            }),
+        ),
+        0 => (
+            "Paranoid (-T0)".dimmed(),
+            Some(ScanSettings {
+                concurrency: 5,
+                timeout: Duration::from_secs(15),
+                max_retries: 5,
+                host_timeout: None,
+            }),
+        ),
+        _ => (
+            "Normal (-T3)".green(),
+            Some(ScanSettings {
+                concurrency: 1000,
+                timeout: Duration::from_millis(800),
+                max_retries: 3,
+                host_timeout: None,
+            }),
+        ),
+    };
+    if !json {
+        match pacing {
+            Some(pacing) => println!(
+                "{} {} {}",
+                "Timing Profile:".bold(),
+                profile,
+                format!("({})", pacing).dimmed()
+            ),
+            None => println!("{} {}", "Timing Profile:".bold(), profile),
+        }
+    }
+    match settings {
+        Some(settings) => settings,
+        None => determine_optimal_settings(probe_target).await,
+    }
+}
+
 #[tokio::main]
 async fn main() {
     tokio_rustls::rustls::crypto::ring::default_provider()
@@ -165,121 +565,926 @@ async fn main() {
 
     let args = Args::parse();
 
+    if let Some(Command::Probes {
+        action: ProbesCommand::Lint { file },
+    }) = &args.command
+    {
+        match fingerprint::lint_probes_file(file) {
+            Ok((probes, matchers)) => {
+                println!(
+                    "{} {} probes, {} matchers",
+                    "OK:".green().bold(),
+                    probes,
+                    matchers
+                );
+                return;
+            }
+            Err(errors) => {
+                for error in errors {
+                    eprintln!("{} {}", "Error:".red().bold(), error);
+                }
+                process::exit(1);
+            }
+        }
+    }
+    let resumed_from = args.resume.clone();
+    let (args, checkpoint) = match &args.resume {
+        Some(path) => {
+            let checkpoint = match checkpoint::Checkpoint::load(path) {
+                Ok(checkpoint) => checkpoint,
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    process::exit(1);
+                }
+            };
+            let program = std::env::args().next().unwrap_or_default();
+            let args = Args::parse_from(std::iter::once(program).chain(checkpoint.args()));
+            (args, Some(checkpoint))
+        }
+        None => {
+            let checkpoint = args
+                .checkpoint
+                .clone()
+                .map(|path| checkpoint::Checkpoint::new(path, std::env::args().skip(1).collect()));
+            (args, checkpoint)
+        }
+    };
     if !args.json {
         println!("{}", ASCII_ART.cyan().bold());
+        if let Some(path) = &resumed_from {
+            println!("{} {}", "Resuming scan from".green(), path.display());
+        }
     }
 
-    let settings = match args.timing {
-        5 => {
-            if !args.json {
-                println!("{} {}", "Timing Profile:".bold(), "Insane (-T5)".red());
+    if let Some(file) = &args.probes_file {
+        match fingerprint::load_probes_file(file) {
+            Ok((probes, matchers)) => {
+                if !args.json {
+                    println!(
+                        "{} {} probes, {} matchers from {}",
+                        "Loaded".dimmed(),
+                        probes,
+                        matchers,
+                        file.display()
+                    );
+                }
             }
-            ScanSettings {
-                concurrency: 5000,
-                timeout: Duration::from_millis(300),
+            Err(errors) => {
+                for error in errors {
+                    eprintln!("Error: {}", error);
+                }
+                process::exit(1);
             }
         }
-        4 => {
-            if !args.json {
-                println!(
-                    "{} {}",
-                    "Timing Profile:".bold(),
-                    "Aggressive (-T4, auto)".yellow()
-                );
+    }
+
+    if let Some(file) = &args.services_file {
+        match services::load_services_file(file) {
+            Ok(count) => {
+                if !args.json {
+                    println!(
+                        "{} {} service names from {}",
+                        "Loaded".dimmed(),
+                        count,
+                        file.display()
+                    );
+                }
+            }
+            Err(error) => {
+                eprintln!("Error: {}", error);
+                process::exit(1);
             }
-            determine_optimal_settings(args.ipaddr).await
         }
-        2 => {
-            if !args.json {
-                println!("{} {}", "Timing Profile:".bold(), "Polite (-T2)".blue());
+    }
+
+    if let Some(file) = &args.os_db {
+        match os::load_signatures_file(file) {
+            Ok(count) => {
+                if !args.json {
+                    println!(
+                        "{} {} OS signatures from {}",
+                        "Loaded".dimmed(),
+                        count,
+                        file.display()
+                    );
+                }
             }
-            ScanSettings {
-                concurrency: 400,
-                timeout: Duration::from_millis(1200),
+            Err(error) => {
+                eprintln!("Error: {}", error);
+                process::exit(1);
             }
         }
-        1 => {
-            if !args.json {
-                println!("{} {}", "Timing Profile:".bold(), "Sneaky (-T1)".dimmed());
+    }
+
+    let transport = if args.udp {
+        services::Protocol::Udp
+    } else {
+        services::Protocol::Tcp
+    };
+    let ports_to_scan = match args.top_ports {
+        Some(count) => services::top_ports(count, transport),
+        None => match parse_port_spec(&args.ports) {
+            Ok(ports) => ports,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                process::exit(1);
             }
-            ScanSettings {
-                concurrency: 100,
-                timeout: Duration::from_secs(5),
+        },
+    };
+
+    let restored = checkpoint.as_ref().and_then(|c| c.targets());
+    let targets = match &restored {
+        Some((targets, _)) => targets.clone(),
+        None => match targets::expand(&args.targets).await {
+            Ok(targets) => targets,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                process::exit(1);
             }
+        },
+    };
+
+    let source = source::Source {
+        ip: args.source_ip,
+        interface: args.interface.clone(),
+        port: args.source_port,
+    };
+    let target_ips: Vec<IpAddr> = targets.iter().map(|t| t.ip).collect();
+    if let Err(e) = source::configure(source, &target_ips, args.udp) {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
+
+    let technique = match args.scan_type.technique() {
+        None => None,
+        Some(technique)
+            if !args.udp && rawscan::supported() && targets.iter().all(|t| t.ip.is_ipv4()) =>
+        {
+            Some(technique)
         }
-        0 => {
-            if !args.json {
-                println!("{} {}", "Timing Profile:".bold(), "Paranoid (-T0)".dimmed());
-            }
-            ScanSettings {
-                concurrency: 5,
-                timeout: Duration::from_secs(15),
+        Some(rawscan::Technique::Syn) => {
+            eprintln!(
+                "Warning: SYN scan needs a TCP scan of IPv4 targets on Linux with root or CAP_NET_RAW; using connect scan"
+            );
+            None
+        }
+        Some(technique) => {
+            eprintln!(
+                "Error: {} scan needs a TCP scan of IPv4 targets on Linux with root or CAP_NET_RAW",
+                technique.name()
+            );
+            process::exit(1);
+        }
+    };
+
+    if let (Some(min_rate), Some(max_rate)) = (args.min_rate, args.max_rate)
+        && min_rate > max_rate
+    {
+        eprintln!("Error: --min-rate must not exceed --max-rate");
+        process::exit(1);
+    }
+    let pacer = pacing::Pacer::new(
+        args.max_rate,
+        args.min_rate,
+        args.scan_delay,
+        args.max_scan_delay,
+    );
+
+    let discovering = restored.is_none() && args.ping.is_none();
+    let statuses = if let Some((_, statuses)) = restored {
+        statuses
+    } else if args.ping == Some(PingMode::Skip) {
+        vec![discovery::HostStatus::assumed_up(); targets.len()]
+    } else {
+        let ping_ports = match parse_port_spec(&args.ping_ports) {
+            Ok(ports) => ports,
+            Err(e) => {
+                eprintln!("Error: --ping-ports: {}", e);
+                process::exit(1);
             }
+        };
+        if !args.json {
+            println!(
+                "\n{} {}...",
+                "Discovering".green(),
+                plural(targets.len(), "host")
+            );
         }
-        _ => {
-            if !args.json {
-                println!("{} {}", "Timing Profile:".bold(), "Normal (-T3)".green());
+        let ips: Vec<IpAddr> = targets.iter().map(|t| t.ip).collect();
+        discovery::discover(&ips, &ping_ports, PING_TIMEOUT, &pacer).await
+    };
+    let up_count = statuses
+        .iter()
+        .filter(|s| s.state == discovery::HostState::Up)
+        .count();
+    if !args.json && discovering {
+        println!(
+            "{} {} of {} up",
+            "Discovery complete:".green(),
+            up_count,
+            plural(targets.len(), "host")
+        );
+    }
+
+    if let Some(checkpoint) = &checkpoint {
+        checkpoint.record_targets(&targets, &statuses);
+        save_checkpoint(checkpoint);
+        let checkpoint = Arc::clone(checkpoint);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(checkpoint::SAVE_INTERVAL);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                save_checkpoint(&checkpoint);
             }
-            ScanSettings {
-                concurrency: 1000,
-                timeout: Duration::from_millis(800),
+        });
+    }
+
+    let first_up = targets
+        .iter()
+        .zip(&statuses)
+        .find(|(_, status)| status.state == discovery::HostState::Up)
+        .map(|(target, _)| target.ip);
+    let progress = if args.json {
+        MultiProgress::with_draw_target(ProgressDrawTarget::hidden())
+    } else {
+        MultiProgress::new()
+    };
+    let shutdown = Arc::new(shutdown::Shutdown::default());
+    let context = match first_up {
+        Some(probe_target) => {
+            let mut settings =
+                timing_settings(args.timing, args.json, probe_target, pacer.describe()).await;
+            apply_overrides(&mut settings, &args);
+            let parallel_hosts = parallel_hosts(&settings, ports_to_scan.len(), up_count, &args);
+            let controls = controls::Controls::new(
+                settings.concurrency,
+                fd_budget().map_or(MAX_LIVE_CONCURRENCY, |budget| {
+                    budget.min(MAX_LIVE_CONCURRENCY)
+                }),
+                Arc::clone(&shutdown),
+            );
+            Some(ScanContext {
+                settings,
+                parallel_hosts,
+                hosts_left: watch::Sender::new(up_count),
+                progress: progress.clone(),
+                technique,
+                transport,
+                ports: ports_to_scan,
+                probe_config: fingerprint::ProbeConfig {
+                    version_intensity: args.version_intensity,
+                    read_limits: fingerprint::ReadLimits {
+                        idle_gap: args.read_idle_gap,
+                        max_size: args.read_size,
+                    },
+                    reply_wait: args.banner_timeout,
+                    pacer: None,
+                },
+                ics_ports: match (args.ics, args.udp) {
+                    (false, _) => &[],
+                    (true, false) => fingerprint::ICS_TCP_PORTS,
+                    (true, true) => fingerprint::ICS_UDP_PORTS,
+                },
+                os_detection: args.os_detection,
+                hide_tarpit_ports: args.hide_tarpit_ports,
+                checkpoint: checkpoint.clone(),
+                shutdown: Arc::clone(&shutdown),
+                controls,
+                json: args.json,
+                pacer: Arc::clone(&pacer),
+            })
+        }
+        None => None,
+    };
+    let settings_report = context.as_ref().map(|context| SettingsReport {
+        timing: args.timing,
+        concurrency: context.settings.concurrency,
+        connect_timeout_ms: context.settings.timeout.as_millis(),
+        banner_timeout_ms: args.banner_timeout.map(|wait| wait.as_millis()),
+        read_size: args.read_size,
+        max_retries: context.settings.max_retries,
+        host_timeout_ms: context.settings.host_timeout.map(|limit| limit.as_millis()),
+        parallel_hosts: context.parallel_hosts,
+    });
+    if let (Some(report), false) = (&settings_report, args.json) {
+        print_settings(report);
+    }
+    let controls = context
+        .as_ref()
+        .map(|context| Arc::clone(&context.controls));
+    {
+        let shutdown = Arc::clone(&shutdown);
+        let controls = controls.clone();
+        let progress = progress.clone();
+        tokio::spawn(async move {
+            shutdown::interrupted().await;
+            shutdown.note_signal();
+            match &controls {
+                Some(controls) => controls.stop(),
+                None => {
+                    shutdown.request();
+                }
             }
+            progress.suspend(|| {
+                eprintln!(
+                    "{}",
+                    "Stopping: letting probes in flight finish. Press Ctrl-C again to quit now."
+                        .yellow()
+                )
+            });
+            shutdown::interrupted().await;
+            controls::restore_terminal();
+            process::exit(130);
+        });
+    }
+    let key_watcher = match (&controls, args.json) {
+        (Some(controls), false) => {
+            let progress = progress.clone();
+            controls::watch_keys(Arc::clone(controls), move |findings| {
+                progress.suspend(|| print_findings(&findings))
+            })
         }
+        _ => None,
     };
+    if key_watcher.is_some() {
+        println!(
+            "{}",
+            "Keys: p pause/resume, +/- concurrency, v per-port events, s findings so far, q stop"
+                .dimmed()
+        );
+    }
 
-    let ports_to_scan = match parse_port_spec(&args.ports) {
-        Ok(ports) => ports,
-        Err(e) => {
-            eprintln!("Error: {}", e);
-            process::exit(1);
+    let protocol = transport.as_str();
+    let mut reports = Vec::new();
+    let mut retries = RetryCounts::default();
+    let mut completed_ports = 0;
+    let mut incomplete = false;
+    // Hosts a stop reached before their scan began, counted instead of reported one by one.
+    let mut not_scanned = 0;
+    let context = context.as_ref();
+    // Hosts are scanned in parallel, a new one starting as soon as any finishes, but
+    // reported in target order.
+    let mut scans = stream::iter(targets.iter().zip(&statuses).enumerate())
+        .map(|(index, (target, status))| async move {
+            let scan = match context {
+                Some(context) if status.state == discovery::HostState::Up => {
+                    let scan = scan_host_within_timeout(target.ip, status.latency, context).await;
+                    context
+                        .hosts_left
+                        .send_modify(|left| *left = left.saturating_sub(1));
+                    Some(scan)
+                }
+                _ => None,
+            };
+            (index, (target, status, scan))
+        })
+        .buffer_unordered(context.map_or(1, |context| context.parallel_hosts));
+    let mut finished = BTreeMap::new();
+    let mut next_report = 0;
+    while let Some((index, host)) = scans.next().await {
+        finished.insert(index, host);
+        while let Some((target, status, scan)) = finished.remove(&next_report) {
+            next_report += 1;
+            let scan = match scan {
+                Some(Ok(scan)) => Some(scan),
+                Some(Err(e)) => {
+                    controls::restore_terminal();
+                    eprintln!("Error: {}", e);
+                    process::exit(1);
+                }
+                None => None,
+            };
+            if let Some(scan) = &scan {
+                retries.retried += scan.retries.retried;
+                retries.recovered += scan.retries.recovered;
+                completed_ports += scan.completed_ports;
+                incomplete |= scan.incomplete;
+            }
+            if args.json {
+                reports.push(host_report(target, status, scan, protocol));
+            } else if let Some(scan) = &scan {
+                if scan.incomplete && scan.completed_ports == 0 {
+                    not_scanned += 1;
+                } else {
+                    progress.suspend(|| print_host_report(target, status, scan, protocol));
+                }
+            } else if targets.len() == 1 {
+                println!(
+                    "\n{}",
+                    format!(
+                        "Note: {} seems down. If it is really up but blocking ping probes, try -Pn",
+                        target.ip
+                    )
+                    .yellow()
+                );
+            }
         }
-    };
+    }
 
-    if !args.json {
+    drop(key_watcher);
+
+    if let Some(checkpoint) = &checkpoint {
+        save_checkpoint(checkpoint);
+        if !args.json {
+            println!(
+                "{} {}",
+                "Checkpoint saved to".dimmed(),
+                checkpoint.path().display().to_string().dimmed()
+            );
+        }
+    }
+
+    let total_ports = up_count * context.map_or(0, |context| context.ports.len());
+    let completed_percent = incomplete
+        .then(|| (completed_ports as f64 * 1000.0 / total_ports.max(1) as f64).round() / 10.0);
+    if args.json {
+        let report = ScanReport {
+            incomplete,
+            completed_percent,
+            settings: settings_report,
+            hosts: reports,
+        };
+        println!("{}", serde_json::to_string_pretty(&report).unwrap());
+    } else {
+        if not_scanned > 0 {
+            println!(
+                "\n{}",
+                format!(
+                    "Not scanned: {} left when the scan was stopped.",
+                    plural(not_scanned, "live host")
+                )
+                .yellow()
+            );
+        }
         println!(
-            "\n{} {} {} {}",
-            "Scanning".green(),
-            args.ipaddr.to_string().bold(),
-            "with".dimmed(),
-            format!("{} concurrent tasks...", settings.concurrency).bold()
+            "\n{} {} scanned ({} up, {} down)",
+            if incomplete {
+                "Stopped:".yellow().bold()
+            } else {
+                "Done:".green().bold()
+            },
+            plural(targets.len(), "host"),
+            up_count,
+            targets.len() - up_count
         );
+        if retries.retried > 0 {
+            println!(
+                "{} {} retried after timeouts, {} answered on retry",
+                "Retries:".bold(),
+                plural(retries.retried, "port"),
+                retries.recovered
+            );
+        }
+        if let Some(percent) = completed_percent {
+            println!(
+                "{}",
+                format!(
+                    "Incomplete: {}% of the scan finished before it was stopped.",
+                    percent
+                )
+                .yellow()
+            );
+        }
     }
+    if incomplete {
+        // 130 as for any program interrupted by a signal; a deliberate `q` is not one.
+        process::exit(if shutdown.was_signalled() { 130 } else { 3 });
+    }
+}
 
-    let num_ports = ports_to_scan.len() as u64;
-    let ip = args.ipaddr;
-    let open_ports = Arc::new(Mutex::new(Vec::<(u16, fingerprint::Fingerprint)>::new()));
+/// Everything that stays the same from one scanned host to the next.
+struct ScanContext {
+    settings: ScanSettings,
+    /// Hosts scanned at once.
+    parallel_hosts: usize,
+    /// Live hosts whose scan has not finished yet.
+    hosts_left: watch::Sender<usize>,
+    /// Holds every host's progress bar; output while bars are drawn goes through `suspend`.
+    progress: MultiProgress,
+    technique: Option<rawscan::Technique>,
+    transport: services::Protocol,
+    ports: Vec<u16>,
+    probe_config: fingerprint::ProbeConfig,
+    ics_ports: &'static [u16],
+    os_detection: bool,
+    hide_tarpit_ports: bool,
+    /// Where finished ports and hosts are recorded, with what an earlier run already did.
+    checkpoint: Option<Arc<checkpoint::Checkpoint>>,
+    shutdown: Arc<shutdown::Shutdown>,
+    /// Pause, concurrency and verbosity, changed from the keyboard while the scan runs.
+    controls: Arc<controls::Controls>,
+    json: bool,
+    pacer: Arc<pacing::Pacer>,
+}
 
-    // --- Setup The Progress Bar ---
-    let pb = if args.json {
-        ProgressBar::hidden()
-    } else {
-        ProgressBar::new(num_ports)
+/// What scanning one live host found.
+#[derive(Default)]
+struct HostScan {
+    ports: Vec<(u16, rawscan::PortState, fingerprint::Fingerprint)>,
+    not_shown: BTreeMap<&'static str, usize>,
+    os_detection: Option<os::OsDetection>,
+    summary: host::HostSummary,
+    retries: RetryCounts,
+    /// `--host-timeout` cut the scan short and its results were discarded.
+    timed_out: bool,
+    /// The scan was stopped before every port was probed.
+    incomplete: bool,
+    /// Ports whose scan finished, out of every port requested.
+    completed_ports: usize,
+}
+
+/// Ports whose connect was retried after a timeout, and how many of them then answered.
+#[derive(Debug, Default, Serialize)]
+struct RetryCounts {
+    retried: usize,
+    recovered: usize,
+}
+
+impl RetryCounts {
+    fn is_empty(&self) -> bool {
+        self.retried == 0
+    }
+}
+
+/// A host's equal share of the concurrency `budget`, split between the hosts still
+/// running: `parallel_hosts` of them, fewer once the last hosts are under way.
+fn host_share(budget: usize, parallel_hosts: usize, hosts_left: usize) -> usize {
+    (budget / parallel_hosts.min(hosts_left).max(1)).max(1)
+}
+
+/// Scans one live host, giving up at `--host-timeout` and returning a scan marked timed out.
+async fn scan_host_within_timeout(
+    ip: IpAddr,
+    latency: Option<Duration>,
+    context: &ScanContext,
+) -> Result<HostScan, String> {
+    let timed_out = HostScan {
+        timed_out: true,
+        completed_ports: context.ports.len(),
+        ..Default::default()
     };
+    let resumed = context.checkpoint.as_ref().map(|c| c.host(ip).finished);
+    if let Some(Some(finished)) = resumed
+        && finished.timed_out
+    {
+        return Ok(timed_out);
+    }
+    let scan = scan_host(ip, latency, context);
+    match context.settings.host_timeout {
+        Some(limit) => within_unpaused(limit, scan, &context.controls)
+            .await
+            .unwrap_or_else(|| {
+                if let Some(checkpoint) = &context.checkpoint {
+                    checkpoint.record_finished(
+                        ip,
+                        checkpoint::Finished {
+                            timed_out: true,
+                            ..Default::default()
+                        },
+                    );
+                }
+                Ok(timed_out)
+            }),
+        None => scan.await,
+    }
+}
 
-    pb.set_style(ProgressStyle::default_bar()
-        .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({percent}%) | ETA: {eta}")
-        .unwrap()
-        .progress_chars("#>-"));
+/// Runs `future` for at most `limit`, not counting time the scan spends paused.
+async fn within_unpaused<T>(
+    limit: Duration,
+    future: impl Future<Output = T>,
+    controls: &controls::Controls,
+) -> Option<T> {
+    let started = tokio::time::Instant::now();
+    let paused_before = controls.paused_time();
+    tokio::pin!(future);
+    loop {
+        let deadline = started + limit + (controls.paused_time() - paused_before);
+        tokio::select! {
+            output = &mut future => return Some(output),
+            _ = tokio::time::sleep_until(deadline) => {
+                // A pause since the deadline was set pushes it back.
+                if started + limit + (controls.paused_time() - paused_before)
+                    <= tokio::time::Instant::now()
+                {
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+/// Scans one live host. `latency`, if discovery measured it, seeds the RTT estimate.
+async fn scan_host(
+    ip: IpAddr,
+    latency: Option<Duration>,
+    context: &ScanContext,
+) -> Result<HostScan, String> {
+    let mut settings = context.settings.clone();
+    let parallel_hosts = context.parallel_hosts;
+    settings.concurrency = host_share(
+        context.controls.state().concurrency,
+        parallel_hosts,
+        *context.hosts_left.borrow(),
+    );
+    let raw_scan = match (context.technique, ip) {
+        (Some(technique), IpAddr::V4(target)) => Some((target, technique)),
+        _ => None,
+    };
+    let saved = context
+        .checkpoint
+        .as_ref()
+        .map(|c| c.host(ip))
+        .unwrap_or_default();
+
+    if !context.json && saved.finished.is_none() && !context.shutdown.is_requested() {
+        let technique = if let Some((_, technique)) = raw_scan {
+            format!(
+                "a {} scan ({} probes in flight)...",
+                technique.name(),
+                settings.concurrency
+            )
+        } else {
+            format!("{} concurrent tasks...", settings.concurrency)
+        };
+        context.progress.suspend(|| {
+            println!(
+                "\n{} {} {} {}",
+                "Scanning".green(),
+                ip.to_string().bold(),
+                "with".dimmed(),
+                technique.bold()
+            )
+        });
+    }
+
+    // Ports a raw scan classified without connecting, reported as-is.
+    let mut unprobed_ports: Vec<(u16, rawscan::PortState)> = Vec::new();
+    // Ports a stopped raw scan never sent to.
+    let mut raw_unsent = 0;
+    let mut not_shown: BTreeMap<&'static str, usize> = BTreeMap::new();
+    let ports_to_scan = match raw_scan {
+        Some((target, technique)) => {
+            let states = match saved.raw_states {
+                Some(states) => states,
+                None if context.shutdown.is_requested() => {
+                    return Ok(HostScan {
+                        incomplete: true,
+                        ..Default::default()
+                    });
+                }
+                None => {
+                    let raw_pb = progress_bar(context.ports.len() as u64, &context.progress);
+                    let sent_pb = raw_pb.clone();
+                    let controls = Arc::clone(&context.controls);
+                    let shutdown = Arc::clone(&context.shutdown);
+                    let states = rawscan::scan(
+                        target,
+                        context.ports.clone(),
+                        technique,
+                        context
+                            .pacer
+                            .limit_batch(settings.concurrency, settings.timeout / 4),
+                        settings.timeout,
+                        move || sent_pb.inc(1),
+                        move || {
+                            // Runs on the sender thread, between batches.
+                            while controls.state().paused && !shutdown.is_requested() {
+                                std::thread::sleep(RAW_PAUSE_POLL);
+                            }
+                            !shutdown.is_requested()
+                        },
+                    )
+                    .await;
+                    raw_pb.finish_and_clear();
+                    let states =
+                        states.map_err(|e| format!("{} scan failed: {}", technique.name(), e))?;
+                    raw_unsent = context.ports.len() - states.len();
+                    // A stopped raw scan is redone in full when the scan resumes.
+                    if let (Some(checkpoint), 0) = (&context.checkpoint, raw_unsent) {
+                        checkpoint.record_raw_states(ip, &states);
+                    }
+                    states
+                }
+            };
+            let split = split_port_states(states);
+            unprobed_ports = split.listed;
+            not_shown = split.hidden;
+            if technique == rawscan::Technique::Syn {
+                split.open
+            } else {
+                // Only a SYN/ACK proves a handshake will work; other techniques just report.
+                let open = split
+                    .open
+                    .into_iter()
+                    .map(|p| (p, rawscan::PortState::Open));
+                unprobed_ports.extend(open);
+                Vec::new()
+            }
+        }
+        None => context.ports.clone(),
+    };
+    // After a SYN scan only the open ports remain, and they are connected to for fingerprinting.
+    let syn_confirmed = raw_scan.is_some_and(|(_, t)| t == rawscan::Technique::Syn);
+    // Skip what an earlier run already probed.
+    let ports_to_scan: Vec<u16> = match &saved.finished {
+        Some(_) => Vec::new(),
+        None => ports_to_scan
+            .into_iter()
+            .filter(|port| !saved.done.contains(port))
+            .collect(),
+    };
+    if syn_confirmed && !context.json && saved.finished.is_none() {
+        context.progress.suspend(|| {
+            println!(
+                "{} {} open ports on {}...",
+                "Fingerprinting".green(),
+                ports_to_scan.len(),
+                ip
+            )
+        });
+    }
+
+    let num_ports = ports_to_scan.len() as u64;
+    let udp = context.transport == services::Protocol::Udp;
+    let ics_ports = context.ics_ports;
+    let open_ports = Arc::new(Mutex::new(saved.open));
+
+    // --- Setup The Progress Bar ---
+    let pb = progress_bar(num_ports, &context.progress);
 
     let pb_clone = pb.clone();
-    let semaphore = Arc::new(Semaphore::new(settings.concurrency));
+    let pacer = context.pacer.for_host();
+    let probe_config = fingerprint::ProbeConfig {
+        pacer: Some(Arc::clone(&pacer)),
+        ..context.probe_config.clone()
+    };
+    let controller = congestion::Controller::new(
+        settings.concurrency,
+        settings.timeout,
+        latency,
+        Arc::clone(&pacer),
+    );
+    // Follow the keyboard controls and the hosts still running: resize the window and
+    // show the state on the bar.
+    let control_watcher = {
+        let controller = Arc::clone(&controller);
+        let pb = pb.clone();
+        let mut control_state = context.controls.subscribe();
+        let mut hosts_left = context.hosts_left.subscribe();
+        let initial_concurrency = context.settings.concurrency;
+        tokio::spawn(async move {
+            loop {
+                let state = *control_state.borrow_and_update();
+                let left = *hosts_left.borrow_and_update();
+                controller.set_max_in_flight(host_share(state.concurrency, parallel_hosts, left));
+                pb.set_message(controls::describe(state, initial_concurrency));
+                tokio::select! {
+                    changed = control_state.changed() => if changed.is_err() { break },
+                    changed = hosts_left.changed() => if changed.is_err() { break },
+                }
+            }
+        })
+    };
+    let _stop_control_watcher = AbortOnDrop(control_watcher.abort_handle());
+    // Room for `+` and finishing hosts to raise this host's share up to the whole budget.
+    let task_limit = context.controls.max_concurrency().max(settings.concurrency);
     let task_open_ports = Arc::clone(&open_ports);
-    let task_semaphore = Arc::clone(&semaphore);
+    let task_controller = Arc::clone(&controller);
+    let retries = Arc::new(Mutex::new(match &saved.finished {
+        Some(finished) => RetryCounts {
+            retried: finished.retried,
+            recovered: finished.recovered,
+        },
+        None => RetryCounts {
+            retried: saved.retried,
+            recovered: saved.recovered,
+        },
+    }));
+    let task_retries = Arc::clone(&retries);
+    let task_checkpoint = context.checkpoint.clone();
+    let task_shutdown = Arc::clone(&context.shutdown);
+    let task_controls = Arc::clone(&context.controls);
+    let task_progress = context.progress.clone();
+    let protocol = context.transport.as_str();
+    let ics_semaphore = Arc::new(Semaphore::new(1));
 
     let scan_handle = tokio::spawn(async move {
         stream::iter(ports_to_scan)
-            .for_each_concurrent(settings.concurrency, |port| {
+            .for_each_concurrent(task_limit, |port| {
                 let open_ports_clone = Arc::clone(&task_open_ports);
-                let semaphore_clone = Arc::clone(&task_semaphore);
+                let controller = Arc::clone(&task_controller);
+                let retries_clone = Arc::clone(&task_retries);
+                let ics_semaphore_clone = Arc::clone(&ics_semaphore);
                 let pb_clone_inner = pb_clone.clone();
+                let checkpoint = task_checkpoint.clone();
+                let shutdown = Arc::clone(&task_shutdown);
+                let controls = Arc::clone(&task_controls);
+                let progress = task_progress.clone();
+                let probe_config = probe_config.clone();
 
                 async move {
-                    let _permit = semaphore_clone.acquire().await.unwrap();
+                    controls.wait_if_paused().await;
+                    if shutdown.is_requested() {
+                        return;
+                    }
                     let socket_addr = SocketAddr::new(ip, port);
+                    // What happened to a port that turns out not to be open, for `v`.
+                    let mut event = "no reply";
 
-                    if let Some(fingerprint) =
-                        fingerprint::probe_port(socket_addr, settings.timeout).await
-                    {
+                    let result = if ics_ports.contains(&port) {
+                        let _ics_permit = tokio::select! {
+                            permit = ics_semaphore_clone.acquire() => permit.unwrap(),
+                            _ = shutdown.requested() => return,
+                        };
+                        // ICS ports queue one at a time; a pause taken meanwhile applies here.
+                        controls.wait_if_paused().await;
+                        let _permit = tokio::select! {
+                            permit = controller.acquire() => permit,
+                            _ = shutdown.requested() => return,
+                        };
+                        let ics_timeout = settings.timeout.max(ICS_POLITE_TIMEOUT);
+                        let result = fingerprint::probe_ics_port(
+                            socket_addr,
+                            ics_timeout,
+                            udp,
+                            probe_config.read_limits,
+                        )
+                        .await;
+                        tokio::time::sleep(ICS_SCAN_DELAY).await;
+                        result
+                    } else if udp {
+                        // The permit only bounds probes in flight; UDP silence is not loss.
+                        let _permit = tokio::select! {
+                            permit = controller.acquire() => permit,
+                            _ = shutdown.requested() => return,
+                        };
+                        fingerprint::probe_udp_port(socket_addr, settings.timeout).await
+                    } else {
+                        let Some((outcome, connect_timeout)) = connect_with_retries(
+                            socket_addr,
+                            &controller,
+                            settings.max_retries,
+                            &retries_clone,
+                            &shutdown,
+                            &controls,
+                        )
+                        .await
+                        else {
+                            return;
+                        };
+                        event = match outcome {
+                            fingerprint::ConnectOutcome::Connected(_) => "no service reply",
+                            fingerprint::ConnectOutcome::Refused => "closed",
+                            fingerprint::ConnectOutcome::TimedOut => "filtered",
+                            fingerprint::ConnectOutcome::Failed => "connect failed",
+                        };
+                        if let fingerprint::ConnectOutcome::Connected(stream) = outcome {
+                            fingerprint::probe_port(
+                                socket_addr,
+                                stream,
+                                connect_timeout,
+                                probe_config,
+                            )
+                            .await
+                        } else {
+                            None
+                        }
+                    };
+                    // The handshake already succeeded once, so keep the port even if the
+                    // follow-up connection is refused or rate limited.
+                    let result = result.or_else(|| {
+                        syn_confirmed.then(|| fingerprint::Fingerprint {
+                            service_name: services::service_name(port, services::Protocol::Tcp),
+                            banner: "[no connection for fingerprinting]".to_string(),
+                            guessed: true,
+                            ..Default::default()
+                        })
+                    });
+                    if let Some(checkpoint) = &checkpoint {
+                        let retries = retries_clone.lock().unwrap();
+                        checkpoint.record_retries(ip, retries.retried, retries.recovered);
+                        checkpoint.record_port(ip, port, result.as_ref());
+                    }
+                    if let Some(fingerprint) = &result {
+                        controls.record_open(
+                            ip,
+                            format!("{}/{} {}", port, protocol, fingerprint.service_name),
+                        );
+                    }
+                    if controls.state().verbose {
+                        let line = match &result {
+                            Some(fingerprint) => format!(
+                                "{}:{}/{} open {}",
+                                ip, port, protocol, fingerprint.service_name
+                            )
+                            .green(),
+                            None => format!("{}:{}/{} {}", ip, port, protocol, event).dimmed(),
+                        };
+                        progress.suspend(|| println!("{}", line));
+                    }
+                    if let Some(fingerprint) = result {
                         open_ports_clone.lock().unwrap().push((port, fingerprint));
                     }
 
@@ -289,57 +1494,528 @@ async fn main() {
             .await;
     });
 
+    // Stops the port tasks too if `--host-timeout` drops this scan midway.
+    let _abort_on_drop = AbortOnDrop(scan_handle.abort_handle());
     scan_handle.await.unwrap();
-    pb.finish_with_message("Scan Complete!");
+    // Ports left unprobed because the scan was stopped.
+    let unprobed = (num_ports - pb.position()) as usize + raw_unsent;
+    pb.finish_and_clear();
+    context.progress.remove(&pb);
+    if controller.drops() > 0 && !context.json {
+        context.progress.suspend(|| {
+            println!(
+                "{}",
+                format!(
+                    "Packet loss detected on {}; window cut {}, ending at {} in flight and a {:?} timeout",
+                    ip,
+                    plural(controller.drops(), "time"),
+                    plural(controller.window(), "connect"),
+                    controller.timeout()
+                )
+                .dimmed()
+            )
+        });
+    }
+
+    let incomplete = unprobed > 0;
+    let completed_ports = context.ports.len() - unprobed;
 
     let mut final_open_ports = open_ports.lock().unwrap().clone();
     final_open_ports.sort_by_key(|&(p, _)| p);
+    let mut final_ports: Vec<(u16, rawscan::PortState, fingerprint::Fingerprint)> =
+        final_open_ports
+            .iter()
+            .map(|(port, fingerprint)| (*port, rawscan::PortState::Open, fingerprint.clone()))
+            .chain(unprobed_ports.iter().map(|&(port, state)| {
+                let fingerprint = fingerprint::Fingerprint {
+                    service_name: services::service_name(port, context.transport),
+                    guessed: true,
+                    ..Default::default()
+                };
+                (port, state, fingerprint)
+            }))
+            .collect();
+    final_ports.sort_by_key(|&(port, _, _)| port);
 
-    if args.json {
-        let report = ScanReport {
-            target: args.ipaddr.to_string(),
-            open_ports: final_open_ports
-                .into_iter()
-                .map(|(port, fingerprint)| PortReport {
-                    port,
-                    state: "open",
-                    service: fingerprint.service_name,
-                    banner: fingerprint.banner,
-                })
-                .collect(),
+    let os_detection = if let Some(finished) = saved.finished {
+        finished.os_detection
+    } else if context.os_detection && !incomplete {
+        // Stack probes need one TCP port that answers and one that refuses.
+        let (open_port, closed_port) = if udp {
+            (None, None)
+        } else {
+            (
+                final_open_ports.first().map(|&(p, _)| p),
+                context
+                    .ports
+                    .iter()
+                    .copied()
+                    .find(|p| !final_open_ports.iter().any(|(open, _)| open == p)),
+            )
         };
-        println!("{}", serde_json::to_string_pretty(&report).unwrap());
+        let stack = match os::probe_stack(ip, open_port, closed_port).await {
+            Ok(stack) => Some(stack),
+            Err(e) => {
+                context
+                    .progress
+                    .suspend(|| eprintln!("Warning: OS stack probes skipped: {}", e));
+                None
+            }
+        };
+        Some(os::guess(stack))
     } else {
-        println!("\n{:-<80}\n", "");
+        None
+    };
+    let mut summary = host::summarize(&final_open_ports, os_detection.as_ref());
+    summary.tarpit = host::detect_tarpit(&final_open_ports, completed_ports);
+    if summary.tarpit.is_some() && context.hide_tarpit_ports {
+        let listed = final_ports.len();
+        final_ports.retain(|(_, state, fingerprint)| {
+            *state != rawscan::PortState::Open || !fingerprint.silent
+        });
+        let hidden = listed - final_ports.len();
+        if hidden > 0 {
+            *not_shown.entry("tarpit").or_default() += hidden;
+        }
+    }
+
+    let retries = std::mem::take(&mut *retries.lock().unwrap());
+    if let (Some(checkpoint), false) = (&context.checkpoint, incomplete) {
+        checkpoint.record_finished(
+            ip,
+            checkpoint::Finished {
+                os_detection: os_detection.clone(),
+                retried: retries.retried,
+                recovered: retries.recovered,
+                timed_out: false,
+            },
+        );
+    }
+    Ok(HostScan {
+        ports: final_ports,
+        not_shown,
+        os_detection,
+        summary,
+        retries,
+        timed_out: false,
+        incomplete,
+        completed_ports,
+    })
+}
+
+/// Connects to `addr` under the congestion window, retrying a timeout up to
+/// `max_retries` times with exponential backoff once the host has shown packet loss.
+/// Returns how the last attempt ended and the timeout it used.
+async fn connect_with_retries(
+    addr: SocketAddr,
+    controller: &Arc<congestion::Controller>,
+    max_retries: u8,
+    retries: &Mutex<RetryCounts>,
+    shutdown: &shutdown::Shutdown,
+    controls: &controls::Controls,
+) -> Option<(fingerprint::ConnectOutcome, Duration)> {
+    let mut attempt: u32 = 0;
+    loop {
+        let permit = tokio::select! {
+            permit = controller.acquire() => permit,
+            // Stopped before the first attempt: the port stays unscanned.
+            _ = shutdown.requested(), if attempt == 0 => return None,
+        };
+        controls.wait_if_paused().await;
+        let connect_timeout = controller.timeout();
+        let outcome = fingerprint::connect_port(addr, connect_timeout).await;
+        match outcome {
+            fingerprint::ConnectOutcome::Connected(_) | fingerprint::ConnectOutcome::Refused => {
+                permit.answered();
+                if attempt > 0 {
+                    retries.lock().unwrap().recovered += 1;
+                }
+                return Some((outcome, connect_timeout));
+            }
+            fingerprint::ConnectOutcome::TimedOut => {
+                permit.timed_out();
+                // Silence from a host that has never lost anything is a filtered port, not a drop.
+                if attempt >= u32::from(max_retries)
+                    || controller.drops() == 0
+                    || shutdown.is_requested()
+                {
+                    return Some((outcome, connect_timeout));
+                }
+                if attempt == 0 {
+                    retries.lock().unwrap().retried += 1;
+                }
+                attempt += 1;
+                let backoff = connect_timeout.saturating_mul(2u32.saturating_pow(attempt - 1));
+                tokio::time::sleep(backoff.min(MAX_RETRY_BACKOFF)).await;
+            }
+            fingerprint::ConnectOutcome::Failed => return Some((outcome, connect_timeout)),
+        }
+    }
+}
 
-        if final_open_ports.is_empty() {
-            println!("No open ports found.");
+fn host_report(
+    target: &targets::Target,
+    status: &discovery::HostStatus,
+    scan: Option<HostScan>,
+    protocol: &'static str,
+) -> HostReport {
+    let scan = scan.unwrap_or_default();
+    let (open_ports, other_ports): (Vec<PortReport>, Vec<PortReport>) = scan
+        .ports
+        .into_iter()
+        .map(|(port, state, fingerprint)| PortReport {
+            port,
+            protocol,
+            state,
+            service: fingerprint.service_name,
+            service_guessed: fingerprint.guessed,
+            banner: fingerprint.banner,
+            details: fingerprint.details,
+        })
+        .partition(|report| report.state == rawscan::PortState::Open);
+    HostReport {
+        target: target.ip.to_string(),
+        hostname: target.hostname.clone(),
+        state: status.state,
+        reason: status.reason,
+        latency_ms: status
+            .latency
+            .map(|latency| (latency.as_secs_f64() * 100_000.0).round() / 100.0),
+        summary: scan.summary,
+        open_ports,
+        other_ports,
+        not_shown: scan.not_shown,
+        os_detection: scan.os_detection,
+        retries: scan.retries,
+        timed_out: scan.timed_out,
+        incomplete: scan.incomplete,
+    }
+}
+
+fn print_host_report(
+    target: &targets::Target,
+    status: &discovery::HostStatus,
+    scan: &HostScan,
+    protocol: &'static str,
+) {
+    println!("\n{:-<80}\n", "");
+    let name = match &target.hostname {
+        Some(hostname) => format!("{} ({})", hostname, target.ip),
+        None => target.ip.to_string(),
+    };
+    println!("{} {}", "Scan report for".bold(), name.bold());
+    let how = match status.latency {
+        Some(latency) => format!("{:.1}ms latency", latency.as_secs_f64() * 1000.0),
+        None => status.reason.to_string(),
+    };
+    println!("{}\n", format!("Host is up ({}).", how).dimmed());
+
+    if scan.timed_out {
+        println!("{}", "Skipped: host timeout reached.".yellow());
+        return;
+    }
+    if scan.incomplete {
+        println!(
+            "{}\n",
+            format!(
+                "Incomplete: scan stopped after {}; results are partial.",
+                plural(scan.completed_ports, "port")
+            )
+            .yellow()
+        );
+    }
+    if let Some(tarpit) = &scan.summary.tarpit {
+        let why = match tarpit.reason {
+            "most-ports-open" => format!(
+                "{} of {} ports accepted connections",
+                tarpit.open_ports, tarpit.scanned_ports
+            ),
+            _ => format!(
+                "{} of {} open ports never sent data",
+                tarpit.silent_ports, tarpit.open_ports
+            ),
+        };
+        println!(
+            "{}\n",
+            format!(
+                "Warning: {}; this host looks like a tarpit or proxy, not real services.",
+                why
+            )
+            .yellow()
+        );
+    }
+    // Every open port hidden by --hide-tarpit-ports gets its own line, not "No open ports".
+    let hidden_tarpit = scan
+        .not_shown
+        .get("tarpit")
+        .copied()
+        .filter(|_| scan.ports.is_empty());
+    if let Some(hidden) = hidden_tarpit {
+        println!(
+            "No ports listed: all {} stayed silent and are hidden as tarpit ports.",
+            plural(hidden, "open port")
+        );
+    } else if scan.ports.is_empty() {
+        println!("No open ports found.");
+    } else {
+        println!(
+            "{:<10} {:<13} {:<15} {}",
+            "PORT".bold(),
+            "STATE".bold(),
+            "SERVICE".bold(),
+            "BANNER".bold()
+        );
+        println!("{:-<10} {:-<13} {:-<15} {:-<50}", "", "", "", "");
+
+        for (port, state, fingerprint) in &scan.ports {
+            let banner_oneline = fingerprint
+                .banner
+                .replace(['\r', '\n'], " ")
+                .trim()
+                .to_string();
+            // Port-table names carry a '?' so they read as guesses, not identifications.
+            let service = if fingerprint.guessed && fingerprint.service_name != "unknown" {
+                format!("{}?", fingerprint.service_name)
+            } else {
+                fingerprint.service_name.clone()
+            };
+            let state_label = match state {
+                rawscan::PortState::Open => state.as_str().green(),
+                rawscan::PortState::Closed => state.as_str().red(),
+                rawscan::PortState::Unfiltered => state.as_str().cyan(),
+                _ => state.as_str().yellow(),
+            };
+            println!(
+                "{:<10} {:<13} {:<15} {}",
+                format!("{}/{}", port, protocol).yellow(),
+                state_label,
+                service.blue(),
+                banner_oneline
+            );
+        }
+    }
+    let counts: Vec<String> = scan
+        .not_shown
+        .iter()
+        .filter(|(state, _)| hidden_tarpit.is_none() || **state != "tarpit")
+        .map(|(state, count)| format!("{} {}", count, state))
+        .collect();
+    if !counts.is_empty() {
+        println!(
+            "{} {} ports",
+            "Not shown:".dimmed(),
+            counts.join(", ").dimmed()
+        );
+    }
+
+    if let Some(detection) = &scan.os_detection {
+        print_os_detection(detection);
+    }
+    if let Some(host_os) = &scan.summary.os {
+        print_host_os(host_os);
+    }
+}
+
+fn print_findings(findings: &[String]) {
+    if findings.is_empty() {
+        println!("{}", "Findings so far: no open ports yet".bold());
+        return;
+    }
+    println!("{}", "Findings so far:".bold());
+    for line in findings {
+        println!("  {}", line);
+    }
+}
+
+fn save_checkpoint(checkpoint: &checkpoint::Checkpoint) {
+    if let Err(e) = checkpoint.save() {
+        eprintln!("Warning: {}", e);
+    }
+}
+
+/// Aborts a spawned task when dropped.
+struct AbortOnDrop(tokio::task::AbortHandle);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+fn print_settings(report: &SettingsReport) {
+    let banner_timeout = match report.banner_timeout_ms {
+        Some(ms) => format!("{}ms", ms),
+        None => "per probe".to_string(),
+    };
+    let host_timeout = match report.host_timeout_ms {
+        Some(ms) => format!("{}ms", ms),
+        None => "none".to_string(),
+    };
+    println!(
+        "{} concurrency {}, connect timeout {}ms, banner timeout {}, read size {} bytes, max retries {}, host timeout {}, {} at once",
+        "Settings:".bold(),
+        report.concurrency,
+        report.connect_timeout_ms,
+        banner_timeout,
+        report.read_size,
+        report.max_retries,
+        host_timeout,
+        plural(report.parallel_hosts, "host")
+    );
+}
+
+fn plural(count: usize, noun: &str) -> String {
+    if count == 1 {
+        format!("{} {}", count, noun)
+    } else {
+        format!("{} {}s", count, noun)
+    }
+}
+
+/// Raw scan results sorted for reporting.
+struct PortStateSplit {
+    open: Vec<u16>,
+    /// Non-open ports worth listing individually.
+    listed: Vec<(u16, rawscan::PortState)>,
+    /// Counts of the remaining ports by state.
+    hidden: BTreeMap<&'static str, usize>,
+}
+
+/// Closed ports are never listed, and neither is any other non-open state shared by
+/// more than `MAX_LISTED_PER_STATE` ports.
+fn split_port_states(states: BTreeMap<u16, rawscan::PortState>) -> PortStateSplit {
+    let mut counts: BTreeMap<&'static str, usize> = BTreeMap::new();
+    for state in states.values() {
+        *counts.entry(state.as_str()).or_default() += 1;
+    }
+    let listed = |state: rawscan::PortState| {
+        state != rawscan::PortState::Closed && counts[state.as_str()] <= MAX_LISTED_PER_STATE
+    };
+
+    let mut split = PortStateSplit {
+        open: Vec::new(),
+        listed: Vec::new(),
+        hidden: BTreeMap::new(),
+    };
+    for (port, state) in states {
+        if state == rawscan::PortState::Open {
+            split.open.push(port);
+        } else if listed(state) {
+            split.listed.push((port, state));
         } else {
+            *split.hidden.entry(state.as_str()).or_default() += 1;
+        }
+    }
+    split
+}
+
+fn progress_bar(len: u64, progress: &MultiProgress) -> ProgressBar {
+    let pb = progress.add(ProgressBar::new(len));
+    pb.set_style(ProgressStyle::default_bar()
+        .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({percent}%) | ETA: {eta} {msg}")
+        .unwrap()
+        .progress_chars("#>-"));
+    pb
+}
+
+fn print_host_os(host_os: &host::HostOs) {
+    let mut conclusion = match (&host_os.family, &host_os.distribution) {
+        (Some(family), Some(distribution)) => format!("{} ({})", family, distribution),
+        (Some(family), None) => family.clone(),
+        (None, _) => "unknown OS".to_string(),
+    };
+    if let Some(device_type) = &host_os.device_type {
+        conclusion = format!("{}, {}", conclusion, device_type);
+    }
+    println!(
+        "\n{} {} {}",
+        "Host:".bold(),
+        conclusion.magenta(),
+        format!("({}% confidence)", host_os.confidence).dimmed()
+    );
+    for evidence in &host_os.evidence {
+        println!("  {} {}", "-".dimmed(), evidence.dimmed());
+    }
+}
+
+fn print_os_detection(detection: &os::OsDetection) {
+    println!();
+    match detection.matches.first() {
+        Some(best) => {
             println!(
-                "{:<10} {:<10} {:<15} {}",
-                "PORT".bold(),
-                "STATE".bold(),
-                "SERVICE".bold(),
-                "BANNER".bold()
+                "{} {} {}",
+                "OS guess:".bold(),
+                best.name.magenta(),
+                format!("({}%)", best.accuracy).dimmed()
             );
-            println!("{:-<10} {:-<10} {:-<15} {:-<50}", "", "", "", "");
-
-            for (port, fingerprint) in final_open_ports {
-                let banner_oneline = fingerprint
-                    .banner
-                    .replace(['\r', '\n'], " ")
-                    .trim()
-                    .to_string();
+            for other in &detection.matches[1..] {
                 println!(
-                    "{:<10} {:<10} {:<15} {}",
-                    format!("{}/tcp", port).yellow(),
-                    "open".green(),
-                    fingerprint.service_name.blue(),
-                    banner_oneline
+                    "          {} {}",
+                    other.name,
+                    format!("({}%)", other.accuracy).dimmed()
                 );
             }
         }
+        None => println!("{} {}", "OS guess:".bold(), "no signature matched".dimmed()),
+    }
+    if let Some(stack) = &detection.stack {
+        let mut traits = Vec::new();
+        if let (Some(ttl), Some(hops)) = (stack.initial_ttl, stack.hops) {
+            traits.push(format!("TTL {} ({} hops)", ttl, hops));
+        }
+        if let Some(window) = stack.window {
+            traits.push(format!("window {}", window));
+        }
+        if let Some(options) = &stack.options {
+            traits.push(format!("options {}", options));
+        }
+        if stack.df == Some(true) {
+            traits.push("DF".to_string());
+        }
+        if let Some(ip_id) = stack.ip_id {
+            traits.push(format!("IP ID {}", ip_id.as_str()));
+        }
+        if !traits.is_empty() {
+            println!("{} {}", "Stack:".bold(), traits.join(", ").dimmed());
+        }
+    }
+}
+
+/// Parses a probe rate, which must be positive.
+fn parse_rate(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(rate) if rate > 0.0 && rate.is_finite() => Ok(rate),
+        _ => Err(format!("'{}' is not a positive number", value)),
+    }
+}
+
+/// Parses a duration such as `500ms`, `2s`, `5m` or `1h`; a bare number is seconds.
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration '{}'", value))?;
+    let seconds = match unit {
+        "ms" => number / 1000.0,
+        "" | "s" => number,
+        "m" => number * 60.0,
+        "h" => number * 3600.0,
+        _ => return Err(format!("unknown unit in '{}'; use ms, s, m or h", value)),
+    };
+    Duration::try_from_secs_f64(seconds).map_err(|_| format!("invalid duration '{}'", value))
+}
+
+/// A duration that must be longer than zero, for timeouts.
+fn parse_positive_duration(value: &str) -> Result<Duration, String> {
+    let duration = parse_duration(value)?;
+    if duration.is_zero() {
+        return Err("must be longer than zero".to_string());
     }
+    Ok(duration)
 }
 
 fn parse_port_spec(spec: &str) -> Result<Vec<u16>, String> {
@@ -376,3 +2052,79 @@ fn parse_port_spec(spec: &str) -> Result<Vec<u16>, String> {
     ports.dedup();
     Ok(ports)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_durations_with_units() {
+        assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_duration("2"), Ok(Duration::from_secs(2)));
+        assert_eq!(parse_duration("1.5s"), Ok(Duration::from_millis(1500)));
+        assert_eq!(parse_duration(" 5m "), Ok(Duration::from_secs(300)));
+        assert_eq!(parse_duration("1h"), Ok(Duration::from_secs(3600)));
+        assert_eq!(parse_duration("0"), Ok(Duration::ZERO));
+    }
+
+    #[test]
+    fn rejects_malformed_durations() {
+        assert_eq!(
+            parse_duration("5d"),
+            Err("unknown unit in '5d'; use ms, s, m or h".to_string())
+        );
+        assert_eq!(
+            parse_duration("ms"),
+            Err("invalid duration 'ms'".to_string())
+        );
+        assert_eq!(
+            parse_duration("-1s"),
+            Err("invalid duration '-1s'".to_string())
+        );
+    }
+
+    #[test]
+    fn parses_port_lists_and_ranges() {
+        assert_eq!(
+            parse_port_spec("443, 80,22-24,80"),
+            Ok(vec![22, 23, 24, 80, 443])
+        );
+        assert_eq!(parse_port_spec("-").unwrap().len(), 65535);
+        assert_eq!(parse_port_spec("65535"), Ok(vec![65535]));
+    }
+
+    #[test]
+    fn rejects_bad_ports() {
+        assert!(parse_port_spec("0").is_err());
+        assert!(parse_port_spec("100-10").is_err());
+        assert!(parse_port_spec("1-65536").is_err());
+        assert_eq!(
+            parse_port_spec("http"),
+            Err("Invalid port: 'http'".to_string())
+        );
+    }
+
+    #[test]
+    fn timeouts_must_be_positive() {
+        assert_eq!(
+            parse_positive_duration("250ms"),
+            Ok(Duration::from_millis(250))
+        );
+        assert_eq!(
+            parse_positive_duration("0ms"),
+            Err("must be longer than zero".to_string())
+        );
+        assert!(Args::try_parse_from(["portdog", "127.0.0.1", "--connect-timeout", "1s"]).is_ok());
+        assert!(Args::try_parse_from(["portdog", "127.0.0.1", "--connect-timeout", "0"]).is_err());
+        assert!(Args::try_parse_from(["portdog", "127.0.0.1", "--host-timeout", "0s"]).is_err());
+    }
+
+    #[test]
+    fn read_idle_gap_is_a_positive_duration() {
+        let args = Args::try_parse_from(["portdog", "127.0.0.1"]).unwrap();
+        assert_eq!(args.read_idle_gap, fingerprint::DEFAULT_READ_IDLE_GAP);
+        let args = Args::try_parse_from(["portdog", "127.0.0.1", "--read-idle-gap", "1s"]).unwrap();
+        assert_eq!(args.read_idle_gap, Duration::from_secs(1));
+        assert!(Args::try_parse_from(["portdog", "127.0.0.1", "--read-idle-gap", "0"]).is_err());
+    }
+}
@@ -0,0 +1,415 @@
+//! Best-effort OS detection from TCP/IP stack behaviour.
+//!
+//! A few crafted SYNs and an ICMP echo are sent over raw sockets; the TTL, window,
+//! option ordering, DF bit and IP ID sequence of the replies are then scored against
+//! the signature database.
+
+use crate::raw::{self, ACK, RST, RawSocket, SYN, TcpOption, TcpReply, TcpSegment};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::Path;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+const EMBEDDED_SIGNATURES: &str = include_str!("../data/os-signatures.toml");
+
+/// Signatures loaded from `--os-db`, consulted alongside the embedded ones.
+static EXTRA_SIGNATURES: OnceLock<Vec<Signature>> = OnceLock::new();
+
+static SIGNATURES: Lazy<Vec<Signature>> = Lazy::new(|| {
+    let mut signatures =
+        parse_signatures(EMBEDDED_SIGNATURES).expect("embedded OS signatures are well formed");
+    signatures.extend(EXTRA_SIGNATURES.get().into_iter().flatten().cloned());
+    signatures
+});
+
+/// How long to wait for replies to the raw probes.
+const OS_REPLY_WAIT: Duration = Duration::from_secs(2);
+/// SYN/ACKs requested from the open port; three are enough to classify the IP ID sequence.
+const OPEN_PORT_SYNS: u16 = 3;
+/// Options sent with every SYN, in the order nmap's first probe uses.
+const PROBE_OPTIONS: &[TcpOption] = &[
+    TcpOption::WindowScale(10),
+    TcpOption::Nop,
+    TcpOption::Mss(1460),
+    TcpOption::Timestamp(0xFFFF_FFFF, 0),
+    TcpOption::SackPermitted,
+];
+/// Only matches at least this accurate are reported.
+const MIN_ACCURACY: u8 = 50;
+
+const TTL_WEIGHT: u32 = 25;
+const WINDOW_WEIGHT: u32 = 20;
+const OPTIONS_WEIGHT: u32 = 30;
+const DF_WEIGHT: u32 = 10;
+const IP_ID_WEIGHT: u32 = 15;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IpIdPattern {
+    Zero,
+    Incremental,
+    Random,
+}
+
+impl IpIdPattern {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            IpIdPattern::Zero => "zero",
+            IpIdPattern::Incremental => "incremental",
+            IpIdPattern::Random => "random",
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SignatureFile {
+    #[serde(default, rename = "os")]
+    signatures: Vec<Signature>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Signature {
+    name: String,
+    family: String,
+    ttl: Option<u8>,
+    #[serde(default)]
+    window: Vec<u16>,
+    options: Option<String>,
+    df: Option<bool>,
+    ip_id: Option<IpIdPattern>,
+}
+
+/// What the target's stack revealed. Fields are absent when no reply carried them.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StackTraits {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub initial_ttl: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hops: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub window: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub options: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub df: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ip_id: Option<IpIdPattern>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OsMatch {
+    pub name: String,
+    pub family: String,
+    /// Percentage of the signature's weighted traits that matched.
+    pub accuracy: u8,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OsDetection {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stack: Option<StackTraits>,
+    pub matches: Vec<OsMatch>,
+}
+
+/// Loads extra signatures from `path`. Must be called before the first guess.
+pub fn load_signatures_file(path: &Path) -> Result<usize, String> {
+    let source = std::fs::read_to_string(path)
+        .map_err(|e| format!("{}: could not read file: {}", path.display(), e))?;
+    let signatures = parse_signatures(&source).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let count = signatures.len();
+    EXTRA_SIGNATURES
+        .set(signatures)
+        .map_err(|_| "OS signatures were already loaded".to_string())?;
+    Ok(count)
+}
+
+fn parse_signatures(source: &str) -> Result<Vec<Signature>, String> {
+    let file: SignatureFile = toml::from_str(source).map_err(|e| {
+        let line = e
+            .span()
+            .map_or(0, |span| source[..span.start].matches('\n').count() + 1);
+        format!("{}: {}", line, e.message().trim())
+    })?;
+    Ok(file.signatures)
+}
+
+/// Sends the raw probes to `ip` and summarises how its stack answered.
+/// `open_port` should accept connections and `closed_port` should refuse them.
+pub async fn probe_stack(
+    ip: IpAddr,
+    open_port: Option<u16>,
+    closed_port: Option<u16>,
+) -> Result<StackTraits, String> {
+    let IpAddr::V4(target) = ip else {
+        return Err("raw-socket probes support IPv4 targets only".to_string());
+    };
+    if !raw::raw_sockets_available() {
+        return Err("raw sockets require root or CAP_NET_RAW".to_string());
+    }
+    tokio::task::spawn_blocking(move || send_probes(target, open_port, closed_port))
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| e.to_string())
+}
+
+fn send_probes(
+    target: Ipv4Addr,
+    open_port: Option<u16>,
+    closed_port: Option<u16>,
+) -> std::io::Result<StackTraits> {
+    let source = raw::source_addr_for(target)?;
+    let tcp = RawSocket::tcp()?;
+    let icmp = RawSocket::icmp()?;
+    let base_port = 40000 + (raw::random_u32() % 20000) as u16;
+    let echo_id = raw::random_u32() as u16;
+
+    let mut probes: Vec<(u16, u16)> = Vec::new();
+    if let Some(port) = open_port {
+        probes.extend((0..OPEN_PORT_SYNS).map(|i| (base_port + i, port)));
+    }
+    if let Some(port) = closed_port {
+        probes.push((base_port + OPEN_PORT_SYNS, port));
+    }
+    for &(src_port, dst_port) in &probes {
+        let segment = TcpSegment {
+            src: source,
+            dst: target,
+            src_port,
+            dst_port,
+            seq: raw::random_u32(),
+            ack: 0,
+            flags: SYN,
+            window: 1024,
+            options: PROBE_OPTIONS,
+        };
+        tcp.send_to(&segment.encode(), target)?;
+    }
+    icmp.send_to(&raw::icmp_echo_request(echo_id, 1, b"portdog"), target)?;
+
+    let deadline = Instant::now() + OS_REPLY_WAIT;
+    let mut replies: Vec<TcpReply> = Vec::new();
+    while replies.len() < probes.len() {
+        let Some(packet) = tcp.recv_until(deadline) else {
+            break;
+        };
+        if let Some(reply) = raw::parse_tcp(&packet)
+            && reply.ip.src == target
+            && probes
+                .iter()
+                .any(|&(src, dst)| reply.dst_port == src && reply.src_port == dst)
+            && !replies.iter().any(|r| r.dst_port == reply.dst_port)
+        {
+            replies.push(reply);
+        }
+    }
+    // The echo reply has usually arrived while we were waiting on TCP.
+    let icmp_deadline = deadline.max(Instant::now() + Duration::from_millis(200));
+    let mut echo = None;
+    while let Some(packet) = icmp.recv_until(icmp_deadline) {
+        if let Some(reply) = raw::parse_icmp(&packet)
+            && reply.ip.src == target
+            && reply.is_echo_reply()
+            && reply.id == echo_id
+        {
+            echo = Some(reply);
+            break;
+        }
+    }
+
+    replies.sort_by_key(|r| r.dst_port);
+    let syn_acks: Vec<&TcpReply> = replies
+        .iter()
+        .filter(|r| r.flags & (SYN | ACK) == SYN | ACK)
+        .collect();
+    let rst = replies.iter().find(|r| r.flags & RST != 0);
+
+    let mut traits = StackTraits::default();
+    let ip = syn_acks
+        .first()
+        .map(|r| r.ip)
+        .or(rst.map(|r| r.ip))
+        .or(echo.map(|r| r.ip));
+    if let Some(ip) = ip {
+        let initial = initial_ttl(ip.ttl);
+        traits.initial_ttl = Some(initial);
+        traits.hops = Some(initial - ip.ttl);
+        traits.df = Some(ip.dont_fragment);
+    }
+    if let Some(first) = syn_acks.first() {
+        traits.window = Some(first.window);
+        traits.options = Some(first.options.iter().map(|o| o.letter()).collect());
+        traits.ip_id = ip_id_pattern(&syn_acks.iter().map(|r| r.ip.id).collect::<Vec<_>>());
+    }
+    Ok(traits)
+}
+
+/// Rounds an observed TTL up to the initial TTL stacks commonly use.
+fn initial_ttl(ttl: u8) -> u8 {
+    [32, 64, 128].into_iter().find(|&t| ttl <= t).unwrap_or(255)
+}
+
+fn ip_id_pattern(ids: &[u16]) -> Option<IpIdPattern> {
+    match ids {
+        [] => None,
+        _ if ids.iter().all(|&id| id == 0) => Some(IpIdPattern::Zero),
+        [_] => None,
+        _ if ids
+            .windows(2)
+            .all(|pair| (1..=1000).contains(&pair[1].wrapping_sub(pair[0]))) =>
+        {
+            Some(IpIdPattern::Incremental)
+        }
+        _ => Some(IpIdPattern::Random),
+    }
+}
+
+/// Scores `stack` against the signature database. Banner evidence is folded in later,
+/// per host, by `host::summarize`.
+pub fn guess(stack: Option<StackTraits>) -> OsDetection {
+    let mut matches: Vec<OsMatch> = match &stack {
+        Some(traits) => SIGNATURES
+            .iter()
+            .filter_map(|signature| score(signature, traits))
+            .map(|(signature, accuracy)| OsMatch {
+                name: signature.name.clone(),
+                family: signature.family.clone(),
+                accuracy,
+            })
+            .filter(|m| m.accuracy >= MIN_ACCURACY)
+            .collect(),
+        None => Vec::new(),
+    };
+    matches.sort_by_key(|m| std::cmp::Reverse(m.accuracy));
+    matches.truncate(3);
+
+    OsDetection { stack, matches }
+}
+
+fn score<'a>(signature: &'a Signature, traits: &StackTraits) -> Option<(&'a Signature, u8)> {
+    let mut possible = 0;
+    let mut matched = 0;
+    let mut compare = |weight: u32, result: Option<bool>| {
+        if let Some(result) = result {
+            possible += weight;
+            if result {
+                matched += weight;
+            }
+        }
+    };
+    compare(
+        TTL_WEIGHT,
+        signature.ttl.map(|ttl| traits.initial_ttl == Some(ttl)),
+    );
+    compare(
+        WINDOW_WEIGHT,
+        (!signature.window.is_empty())
+            .then(|| traits.window.is_some_and(|w| signature.window.contains(&w))),
+    );
+    compare(
+        OPTIONS_WEIGHT,
+        signature
+            .options
+            .as_ref()
+            .map(|o| traits.options.as_ref() == Some(o)),
+    );
+    compare(DF_WEIGHT, signature.df.map(|df| traits.df == Some(df)));
+    compare(
+        IP_ID_WEIGHT,
+        signature.ip_id.map(|id| traits.ip_id == Some(id)),
+    );
+    if possible == 0 {
+        return None;
+    }
+    Some((signature, (matched * 100 / possible) as u8))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_ip_id_sequences() {
+        assert_eq!(ip_id_pattern(&[]), None);
+        assert_eq!(ip_id_pattern(&[0, 0, 0]), Some(IpIdPattern::Zero));
+        assert_eq!(ip_id_pattern(&[0]), Some(IpIdPattern::Zero));
+        assert_eq!(ip_id_pattern(&[4711]), None);
+        assert_eq!(
+            ip_id_pattern(&[100, 101, 357]),
+            Some(IpIdPattern::Incremental)
+        );
+        // Wrapping past 65535 is still a step of one.
+        assert_eq!(
+            ip_id_pattern(&[65535, 0, 1]),
+            Some(IpIdPattern::Incremental)
+        );
+        assert_eq!(ip_id_pattern(&[100, 101, 100]), Some(IpIdPattern::Random));
+        assert_eq!(
+            ip_id_pattern(&[1200, 48011, 9374]),
+            Some(IpIdPattern::Random)
+        );
+    }
+
+    #[test]
+    fn rounds_ttls_up_to_common_initial_values() {
+        assert_eq!(initial_ttl(57), 64);
+        assert_eq!(initial_ttl(64), 64);
+        assert_eq!(initial_ttl(117), 128);
+        assert_eq!(initial_ttl(30), 32);
+        assert_eq!(initial_ttl(240), 255);
+    }
+
+    #[test]
+    fn embedded_signatures_parse() {
+        let signatures = parse_signatures(EMBEDDED_SIGNATURES).unwrap();
+        assert!(signatures.iter().any(|s| s.family == "Linux"));
+        assert!(signatures.iter().any(|s| s.family == "Windows"));
+    }
+
+    #[test]
+    fn signature_errors_carry_the_line() {
+        let source = "[[os]]\nname = \"x\"\nfamily = \"y\"\nwindows = [1]\n";
+        let error = parse_signatures(source).unwrap_err();
+        assert!(error.starts_with("4: "), "{}", error);
+    }
+
+    #[test]
+    fn scores_only_the_traits_a_signature_names() {
+        let signature = parse_signatures(
+            "[[os]]\nname = \"Linux\"\nfamily = \"Linux\"\nttl = 64\noptions = \"MSTNW\"\n",
+        )
+        .unwrap()
+        .remove(0);
+        let mut traits = StackTraits {
+            initial_ttl: Some(64),
+            window: Some(1),
+            options: Some("MSTNW".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(score(&signature, &traits).unwrap().1, 100);
+        traits.options = Some("MNWST".to_string());
+        // Only the TTL matches: 25 of the 55 points at stake.
+        assert_eq!(score(&signature, &traits).unwrap().1, 45);
+    }
+
+    #[test]
+    fn guesses_a_linux_stack() {
+        let detection = guess(Some(StackTraits {
+            initial_ttl: Some(64),
+            window: Some(64240),
+            options: Some("MSTNW".to_string()),
+            df: Some(true),
+            ip_id: Some(IpIdPattern::Zero),
+            ..Default::default()
+        }));
+        let best = &detection.matches[0];
+        assert_eq!(
+            (best.name.as_str(), best.accuracy),
+            ("Linux 4.x - 6.x", 100)
+        );
+        assert!(guess(None).matches.is_empty());
+    }
+}
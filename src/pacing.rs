@@ -0,0 +1,197 @@
+//! Pacing of probes. `--max-rate` is a token bucket and `--min-rate` a floor that
+//! congestion control honours, both shared by every target; `--scan-delay` is a minimum
+//! gap between one host's probes that grows towards `--max-scan-delay` while that host
+//! drops them.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// How much sending a full bucket allows in one burst under `--max-rate`.
+const BURST_WINDOW: Duration = Duration::from_millis(50);
+/// First step when a zero scan delay is raised after a drop.
+const FIRST_DELAY_STEP: Duration = Duration::from_millis(5);
+
+pub struct Pacer {
+    max_rate: Option<f64>,
+    min_rate: Option<f64>,
+    base_delay: Duration,
+    max_delay: Duration,
+    state: Mutex<State>,
+}
+
+struct State {
+    tokens: f64,
+    refilled: Instant,
+    /// When the most recently scheduled probe goes out, possibly in the future.
+    last_send: Option<Instant>,
+}
+
+/// One host's view of the pacer, holding its scan delay.
+pub struct HostPacer {
+    shared: Arc<Pacer>,
+    state: Mutex<DelayState>,
+}
+
+struct DelayState {
+    /// When this host's most recently scheduled probe goes out.
+    last_send: Option<Instant>,
+    delay: Duration,
+}
+
+impl Pacer {
+    pub fn new(
+        max_rate: Option<f64>,
+        min_rate: Option<f64>,
+        scan_delay: Option<Duration>,
+        max_scan_delay: Option<Duration>,
+    ) -> Arc<Self> {
+        let base_delay = scan_delay.unwrap_or_default();
+        Arc::new(Pacer {
+            max_rate,
+            min_rate,
+            base_delay,
+            max_delay: max_scan_delay.unwrap_or(base_delay).max(base_delay),
+            state: Mutex::new(State {
+                tokens: 1.0,
+                refilled: Instant::now(),
+                last_send: None,
+            }),
+        })
+    }
+
+    /// A pacer for one host: its scan delay grows with its own drops only.
+    pub fn for_host(self: &Arc<Self>) -> Arc<HostPacer> {
+        Arc::new(HostPacer {
+            shared: Arc::clone(self),
+            state: Mutex::new(DelayState {
+                last_send: None,
+                delay: self.base_delay,
+            }),
+        })
+    }
+
+    fn is_active(&self) -> bool {
+        self.max_rate.is_some() || self.min_rate.is_some() || !self.max_delay.is_zero()
+    }
+
+    /// Waits until the rate limits allow the next probe.
+    pub async fn wait(&self) {
+        if !self.is_active() {
+            return;
+        }
+        let send_at = self.reserve(&mut self.state.lock().unwrap());
+        tokio::time::sleep_until(send_at).await;
+    }
+
+    /// When `--min-rate` says the next probe is due regardless of congestion control.
+    pub fn min_rate_deadline(&self) -> Option<Instant> {
+        let rate = self.min_rate?;
+        let last_send = self.state.lock().unwrap().last_send;
+        Some(last_send.unwrap_or_else(Instant::now) + Duration::from_secs_f64(1.0 / rate))
+    }
+
+    /// Schedules a probe if `--min-rate` says one is overdue, returning when to send it.
+    /// Only one caller wins each overdue slot.
+    pub fn claim_overdue(&self) -> Option<Instant> {
+        let mut state = self.state.lock().unwrap();
+        let rate = self.min_rate?;
+        let due =
+            state.last_send.unwrap_or_else(Instant::now) + Duration::from_secs_f64(1.0 / rate);
+        (Instant::now() >= due).then(|| self.reserve(&mut state))
+    }
+
+    fn reserve(&self, state: &mut State) -> Instant {
+        let mut send_at = Instant::now();
+        if let Some(last) = state.last_send {
+            send_at = send_at.max(last);
+        }
+        if let Some(rate) = self.max_rate {
+            let burst = (rate * BURST_WINDOW.as_secs_f64()).max(1.0);
+            let earned = send_at
+                .saturating_duration_since(state.refilled)
+                .as_secs_f64()
+                * rate;
+            let mut tokens = (state.tokens + earned).min(burst);
+            if tokens < 1.0 {
+                send_at += Duration::from_secs_f64((1.0 - tokens) / rate);
+                tokens = 1.0;
+            }
+            state.tokens = tokens - 1.0;
+            state.refilled = send_at;
+        }
+        state.last_send = Some(send_at);
+        send_at
+    }
+
+    /// Caps a raw scan's batch of `window` probes per `interval` to the configured rate.
+    pub fn limit_batch(&self, window: usize, interval: Duration) -> usize {
+        let mut window = window as f64;
+        if let Some(rate) = self.max_rate {
+            window = window.min(rate * interval.as_secs_f64());
+        }
+        if !self.base_delay.is_zero() {
+            window = window.min(interval.as_secs_f64() / self.base_delay.as_secs_f64());
+        }
+        (window as usize).max(1)
+    }
+
+    /// The limits in force, for the timing banner.
+    pub fn describe(&self) -> Option<String> {
+        let mut parts = Vec::new();
+        if let Some(rate) = self.max_rate {
+            parts.push(format!("max {} probes/s", rate));
+        }
+        if let Some(rate) = self.min_rate {
+            parts.push(format!("min {} probes/s", rate));
+        }
+        if self.max_delay > self.base_delay {
+            parts.push(format!(
+                "scan delay {:?} up to {:?}",
+                self.base_delay, self.max_delay
+            ));
+        } else if !self.base_delay.is_zero() {
+            parts.push(format!("scan delay {:?}", self.base_delay));
+        }
+        (!parts.is_empty()).then(|| parts.join(", "))
+    }
+}
+
+impl HostPacer {
+    /// Waits until the next probe to this host may be sent.
+    pub async fn wait(&self) {
+        if !self.shared.is_active() {
+            return;
+        }
+        let send_at = {
+            let mut state = self.state.lock().unwrap();
+            let mut send_at = Instant::now();
+            if let Some(last) = state.last_send {
+                send_at = send_at.max(last + state.delay);
+            }
+            state.last_send = Some(send_at);
+            send_at
+        };
+        tokio::time::sleep_until(send_at).await;
+        self.shared.wait().await;
+    }
+
+    pub fn min_rate_deadline(&self) -> Option<Instant> {
+        self.shared.min_rate_deadline()
+    }
+
+    pub fn claim_overdue(&self) -> Option<Instant> {
+        self.shared.claim_overdue()
+    }
+
+    /// Lengthens the scan delay after a drop, up to `--max-scan-delay`.
+    pub fn slow_down(&self) {
+        let mut state = self.state.lock().unwrap();
+        let raised = if state.delay.is_zero() {
+            FIRST_DELAY_STEP
+        } else {
+            state.delay * 2
+        };
+        state.delay = raised.min(self.shared.max_delay);
+    }
+}
@@ -0,0 +1,437 @@
+//! Raw IPv4 sockets and the TCP/ICMP packet encoding needed to send crafted probes.
+//!
+//! Outgoing packets carry only the transport header; the kernel fills in the IP header.
+//! Incoming packets are returned with their IP header so TTL, DF and IP ID can be read.
+
+use crate::source;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io::{self, Read};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::Instant;
+
+pub const FIN: u8 = 0x01;
+pub const SYN: u8 = 0x02;
+pub const RST: u8 = 0x04;
+pub const PSH: u8 = 0x08;
+pub const ACK: u8 = 0x10;
+pub const URG: u8 = 0x20;
+
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_DEST_UNREACHABLE: u8 = 3;
+const ICMP_ECHO_REQUEST: u8 = 8;
+/// Unreachable codes that mean a filter dropped the packet: host, protocol and port
+/// unreachable, and the three "administratively prohibited" codes.
+const ICMP_FILTERED_CODES: [u8; 6] = [1, 2, 3, 9, 10, 13];
+
+pub struct RawSocket(Socket);
+
+impl RawSocket {
+    pub fn tcp() -> io::Result<Self> {
+        Self::new(Protocol::TCP)
+    }
+
+    pub fn icmp() -> io::Result<Self> {
+        Self::new(Protocol::ICMPV4)
+    }
+
+    fn new(protocol: Protocol) -> io::Result<Self> {
+        let socket = Socket::new(Domain::IPV4, Type::RAW, Some(protocol))?;
+        source::bind_raw(&socket)?;
+        Ok(RawSocket(socket))
+    }
+
+    /// Enlarges the kernel receive queue so bursts of replies are not dropped.
+    pub fn set_recv_buffer_size(&self, size: usize) -> io::Result<()> {
+        self.0.set_recv_buffer_size(size)
+    }
+
+    pub fn send_to(&self, packet: &[u8], dst: Ipv4Addr) -> io::Result<()> {
+        let addr = SocketAddr::V4(SocketAddrV4::new(dst, 0));
+        self.0.send_to(packet, &addr.into()).map(|_| ())
+    }
+
+    /// Blocks for the next packet, giving up at `deadline`.
+    pub fn recv_until(&self, deadline: Instant) -> Option<Vec<u8>> {
+        let remaining = deadline.checked_duration_since(Instant::now())?;
+        if remaining.is_zero() {
+            return None;
+        }
+        self.0.set_read_timeout(Some(remaining)).ok()?;
+        let mut buffer = [0u8; 1500];
+        let read = (&self.0).read(&mut buffer).ok()?;
+        Some(buffer[..read].to_vec())
+    }
+}
+
+/// True when this process may open raw sockets (root or `CAP_NET_RAW`).
+pub fn raw_sockets_available() -> bool {
+    RawSocket::tcp().is_ok()
+}
+
+/// The local address the kernel would use to reach `dst`, needed for TCP checksums.
+pub fn source_addr_for(dst: Ipv4Addr) -> io::Result<Ipv4Addr> {
+    if let Some(IpAddr::V4(source)) = source::ip() {
+        return Ok(source);
+    }
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, None)?;
+    source::bind_lookup(&socket)?;
+    socket.connect(&SocketAddr::from((dst, 9)).into())?;
+    match socket.local_addr()?.as_socket() {
+        Some(SocketAddr::V4(addr)) => Ok(*addr.ip()),
+        _ => Err(io::Error::other("no IPv4 route to target")),
+    }
+}
+
+/// A random value from the OS for sequence numbers, IDs and source ports, so replies
+/// can't be predicted or spoofed.
+pub fn random_u32() -> u32 {
+    getrandom::u32().expect("the OS random number generator is available")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TcpOption {
+    Eol,
+    Nop,
+    Mss(u16),
+    WindowScale(u8),
+    SackPermitted,
+    Timestamp(u32, u32),
+}
+
+impl TcpOption {
+    /// Single-letter code used to describe option ordering (nmap style).
+    pub fn letter(self) -> char {
+        match self {
+            TcpOption::Eol => 'L',
+            TcpOption::Nop => 'N',
+            TcpOption::Mss(_) => 'M',
+            TcpOption::WindowScale(_) => 'W',
+            TcpOption::SackPermitted => 'S',
+            TcpOption::Timestamp(..) => 'T',
+        }
+    }
+
+    fn encode(self, out: &mut Vec<u8>) {
+        match self {
+            TcpOption::Eol => out.push(0),
+            TcpOption::Nop => out.push(1),
+            TcpOption::Mss(mss) => {
+                out.extend_from_slice(&[2, 4]);
+                out.extend_from_slice(&mss.to_be_bytes());
+            }
+            TcpOption::WindowScale(shift) => out.extend_from_slice(&[3, 3, shift]),
+            TcpOption::SackPermitted => out.extend_from_slice(&[4, 2]),
+            TcpOption::Timestamp(value, echo) => {
+                out.extend_from_slice(&[8, 10]);
+                out.extend_from_slice(&value.to_be_bytes());
+                out.extend_from_slice(&echo.to_be_bytes());
+            }
+        }
+    }
+}
+
+/// Fields of an outgoing TCP segment.
+pub struct TcpSegment<'a> {
+    pub src: Ipv4Addr,
+    pub dst: Ipv4Addr,
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub seq: u32,
+    pub ack: u32,
+    pub flags: u8,
+    pub window: u16,
+    pub options: &'a [TcpOption],
+}
+
+impl TcpSegment<'_> {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut options = Vec::new();
+        for option in self.options {
+            option.encode(&mut options);
+        }
+        while options.len() % 4 != 0 {
+            options.push(0);
+        }
+        let header_len = 20 + options.len();
+
+        let mut segment = Vec::with_capacity(header_len);
+        segment.extend_from_slice(&self.src_port.to_be_bytes());
+        segment.extend_from_slice(&self.dst_port.to_be_bytes());
+        segment.extend_from_slice(&self.seq.to_be_bytes());
+        segment.extend_from_slice(&self.ack.to_be_bytes());
+        segment.push(((header_len / 4) as u8) << 4);
+        segment.push(self.flags);
+        segment.extend_from_slice(&self.window.to_be_bytes());
+        segment.extend_from_slice(&[0, 0, 0, 0]); // checksum, urgent pointer
+        segment.extend_from_slice(&options);
+
+        let mut pseudo = Vec::with_capacity(12 + segment.len());
+        pseudo.extend_from_slice(&self.src.octets());
+        pseudo.extend_from_slice(&self.dst.octets());
+        pseudo.extend_from_slice(&[0, 6]);
+        pseudo.extend_from_slice(&(segment.len() as u16).to_be_bytes());
+        pseudo.extend_from_slice(&segment);
+        segment[16..18].copy_from_slice(&checksum(&pseudo).to_be_bytes());
+        segment
+    }
+}
+
+/// IP header fields that reveal how the remote stack builds packets.
+#[derive(Debug, Clone, Copy)]
+pub struct Ipv4Info {
+    pub src: Ipv4Addr,
+    pub ttl: u8,
+    pub dont_fragment: bool,
+    pub id: u16,
+}
+
+#[derive(Debug, Clone)]
+pub struct TcpReply {
+    pub ip: Ipv4Info,
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub flags: u8,
+    pub window: u16,
+    pub options: Vec<TcpOption>,
+}
+
+#[derive(Debug, Clone)]
+pub struct IcmpReply {
+    pub ip: Ipv4Info,
+    pub icmp_type: u8,
+    pub code: u8,
+    pub id: u16,
+    /// For error messages, the start of the datagram that triggered them.
+    pub quoted: Vec<u8>,
+}
+
+/// Splits an IPv4 packet into its header fields and payload, if it carries `protocol`.
+fn parse_ipv4(packet: &[u8], protocol: u8) -> Option<(Ipv4Info, &[u8])> {
+    if packet.len() < 20 || packet[0] >> 4 != 4 || packet[9] != protocol {
+        return None;
+    }
+    let header_len = usize::from(packet[0] & 0x0F) * 4;
+    let total_len = usize::from(u16::from_be_bytes([packet[2], packet[3]]));
+    let end = total_len.clamp(header_len, packet.len());
+    let info = Ipv4Info {
+        src: Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]),
+        ttl: packet[8],
+        dont_fragment: packet[6] & 0x40 != 0,
+        id: u16::from_be_bytes([packet[4], packet[5]]),
+    };
+    Some((info, packet.get(header_len..end)?))
+}
+
+pub fn parse_tcp(packet: &[u8]) -> Option<TcpReply> {
+    let (ip, segment) = parse_ipv4(packet, 6)?;
+    if segment.len() < 20 {
+        return None;
+    }
+    let header_len = usize::from(segment[12] >> 4) * 4;
+    let options = parse_tcp_options(segment.get(20..header_len)?);
+    Some(TcpReply {
+        ip,
+        src_port: u16::from_be_bytes([segment[0], segment[1]]),
+        dst_port: u16::from_be_bytes([segment[2], segment[3]]),
+        flags: segment[13],
+        window: u16::from_be_bytes([segment[14], segment[15]]),
+        options,
+    })
+}
+
+fn parse_tcp_options(mut bytes: &[u8]) -> Vec<TcpOption> {
+    let mut options = Vec::new();
+    while let Some(&kind) = bytes.first() {
+        match kind {
+            0 => {
+                options.push(TcpOption::Eol);
+                bytes = &bytes[1..];
+                continue;
+            }
+            1 => {
+                options.push(TcpOption::Nop);
+                bytes = &bytes[1..];
+                continue;
+            }
+            _ => {}
+        }
+        let Some(&len) = bytes.get(1) else { break };
+        let len = usize::from(len);
+        let Some(body) = bytes.get(2..len.max(2)) else {
+            break;
+        };
+        let option = match (kind, body.len()) {
+            (2, 2) => Some(TcpOption::Mss(u16::from_be_bytes([body[0], body[1]]))),
+            (3, 1) => Some(TcpOption::WindowScale(body[0])),
+            (4, 0) => Some(TcpOption::SackPermitted),
+            (8, 8) => Some(TcpOption::Timestamp(
+                u32::from_be_bytes([body[0], body[1], body[2], body[3]]),
+                u32::from_be_bytes([body[4], body[5], body[6], body[7]]),
+            )),
+            _ => None,
+        };
+        options.extend(option);
+        bytes = &bytes[len.max(2)..];
+    }
+    options
+}
+
+pub fn icmp_echo_request(id: u16, seq: u16, payload: &[u8]) -> Vec<u8> {
+    let mut packet = vec![ICMP_ECHO_REQUEST, 0, 0, 0];
+    packet.extend_from_slice(&id.to_be_bytes());
+    packet.extend_from_slice(&seq.to_be_bytes());
+    packet.extend_from_slice(payload);
+    let sum = checksum(&packet);
+    packet[2..4].copy_from_slice(&sum.to_be_bytes());
+    packet
+}
+
+pub fn parse_icmp(packet: &[u8]) -> Option<IcmpReply> {
+    let (ip, message) = parse_ipv4(packet, 1)?;
+    if message.len() < 8 {
+        return None;
+    }
+    Some(IcmpReply {
+        ip,
+        icmp_type: message[0],
+        code: message[1],
+        id: u16::from_be_bytes([message[4], message[5]]),
+        quoted: message[8..].to_vec(),
+    })
+}
+
+impl IcmpReply {
+    pub fn is_echo_reply(&self) -> bool {
+        self.icmp_type == ICMP_ECHO_REPLY
+    }
+
+    /// For an unreachable that signals filtering, the destination address and TCP
+    /// ports of the quoted probe.
+    pub fn filtered_tcp_probe(&self) -> Option<(Ipv4Addr, u16, u16)> {
+        if self.icmp_type != ICMP_DEST_UNREACHABLE || !ICMP_FILTERED_CODES.contains(&self.code) {
+            return None;
+        }
+        let quoted = &self.quoted;
+        if quoted.len() < 20 || quoted[9] != 6 {
+            return None;
+        }
+        let header_len = usize::from(quoted[0] & 0x0F) * 4;
+        let ports = quoted.get(header_len..header_len + 4)?;
+        Some((
+            Ipv4Addr::new(quoted[16], quoted[17], quoted[18], quoted[19]),
+            u16::from_be_bytes([ports[0], ports[1]]),
+            u16::from_be_bytes([ports[2], ports[3]]),
+        ))
+    }
+}
+
+/// The RFC 1071 internet checksum.
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data
+        .chunks(2)
+        .map(|pair| u32::from(u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)])))
+        .sum();
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ipv4_packet(protocol: u8, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![
+            0x45, 0x00, 0x00, 0x00, 0x12, 0x34, 0x40, 0x00, 57, protocol, 0x00, 0x00, 10, 0, 0, 1,
+            10, 0, 0, 2,
+        ];
+        let total = (20 + payload.len()) as u16;
+        packet[2..4].copy_from_slice(&total.to_be_bytes());
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    #[test]
+    fn parses_syn_ack_options_in_order() {
+        let options = [
+            2, 4, 0x05, 0xB4, // MSS 1460
+            4, 2, // SACK permitted
+            8, 10, 0, 0, 0, 1, 0, 0, 0, 2, // Timestamp 1, 2
+            1, // NOP
+            3, 3, 7, // Window scale 7
+        ];
+        assert_eq!(
+            parse_tcp_options(&options),
+            [
+                TcpOption::Mss(1460),
+                TcpOption::SackPermitted,
+                TcpOption::Timestamp(1, 2),
+                TcpOption::Nop,
+                TcpOption::WindowScale(7),
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_unknown_options_and_stops_at_truncation() {
+        // An unknown option 30 of length 4, a NOP, then an MSS cut short.
+        assert_eq!(
+            parse_tcp_options(&[30, 4, 0, 0, 1, 2, 4, 0x05]),
+            [TcpOption::Nop]
+        );
+        // A zero length must not loop forever.
+        assert_eq!(parse_tcp_options(&[5, 0, 1]), [TcpOption::Nop]);
+    }
+
+    #[test]
+    fn parses_tcp_reply() {
+        let mut segment = vec![0; 24];
+        segment[0..2].copy_from_slice(&443u16.to_be_bytes());
+        segment[2..4].copy_from_slice(&40000u16.to_be_bytes());
+        segment[12] = 6 << 4;
+        segment[13] = SYN | ACK;
+        segment[14..16].copy_from_slice(&64240u16.to_be_bytes());
+        segment[20..24].copy_from_slice(&[2, 4, 0x05, 0xB4]);
+        let reply = parse_tcp(&ipv4_packet(6, &segment)).unwrap();
+        assert_eq!((reply.src_port, reply.dst_port), (443, 40000));
+        assert_eq!((reply.flags, reply.window), (SYN | ACK, 64240));
+        assert_eq!(reply.options, [TcpOption::Mss(1460)]);
+        assert_eq!((reply.ip.ttl, reply.ip.id), (57, 0x1234));
+        assert!(reply.ip.dont_fragment);
+        assert_eq!(reply.ip.src, Ipv4Addr::new(10, 0, 0, 1));
+
+        assert!(parse_tcp(&ipv4_packet(17, &segment)).is_none());
+        assert!(parse_tcp(&ipv4_packet(6, &segment[..19])).is_none());
+    }
+
+    #[test]
+    fn echo_request_checksum_verifies() {
+        let packet = icmp_echo_request(0x5044, 1, b"portdog");
+        assert_eq!(&packet[..2], &[ICMP_ECHO_REQUEST, 0]);
+        assert_eq!(checksum(&packet), 0);
+        // The RFC 1071 worked example, with an odd trailing byte padded.
+        assert_eq!(
+            checksum(&[0x00, 0x01, 0xF2, 0x03, 0xF4, 0xF5, 0xF6, 0xF7]),
+            !0xDDF2
+        );
+        assert_eq!(checksum(&[0xFF]), !0xFF00);
+    }
+
+    #[test]
+    fn extracts_the_probe_quoted_by_an_unreachable() {
+        let mut quoted = ipv4_packet(6, &[0x9C, 0x40, 0x00, 0x50, 0, 0, 0, 0]);
+        quoted[16..20].copy_from_slice(&[192, 0, 2, 7]);
+        let message = [&[ICMP_DEST_UNREACHABLE, 13, 0, 0, 0, 0, 0, 0][..], &quoted].concat();
+        let reply = parse_icmp(&ipv4_packet(1, &message)).unwrap();
+        assert_eq!(
+            reply.filtered_tcp_probe(),
+            Some((Ipv4Addr::new(192, 0, 2, 7), 40000, 80))
+        );
+
+        // Fragmentation needed says nothing about filtering.
+        let message = [&[ICMP_DEST_UNREACHABLE, 4, 0, 0, 0, 0, 0, 0][..], &quoted].concat();
+        let reply = parse_icmp(&ipv4_packet(1, &message)).unwrap();
+        assert_eq!(reply.filtered_tcp_probe(), None);
+    }
+}
@@ -0,0 +1,340 @@
+//! Port scanning with hand-built packets instead of full connects.
+//!
+//! A sender thread emits one probe per port from a single source port, resending to
+//! ports that stayed silent, while receiver threads classify the TCP replies and ICMP
+//! errors, so no file descriptor is held per port and the target's applications never
+//! see a completed connection.
+
+use crate::raw::{self, ACK, FIN, PSH, RST, RawSocket, SYN, TcpReply, TcpSegment, URG};
+use crate::source;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::io;
+use std::net::Ipv4Addr;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// How often the receivers wake to check whether the scan is over.
+const RECEIVE_POLL: Duration = Duration::from_millis(50);
+/// Receive queue for the scanning socket; a full port range answers with ~65k packets.
+const RECEIVE_BUFFER_SIZE: usize = 16 * 1024 * 1024;
+/// Extra rounds of probes for ports that have not answered, so one lost packet doesn't
+/// make a port look filtered.
+const RETRANSMISSIONS: usize = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PortState {
+    #[serde(rename = "open")]
+    Open,
+    #[serde(rename = "closed")]
+    Closed,
+    /// A filter dropped the probe or answered with an ICMP unreachable.
+    #[serde(rename = "filtered")]
+    Filtered,
+    /// Reachable through the firewall, but open or closed is unknown (ACK scan).
+    #[serde(rename = "unfiltered")]
+    Unfiltered,
+    /// No reply, which an open port and a filter both produce (FIN/NULL/Xmas scans).
+    #[serde(rename = "open|filtered")]
+    OpenFiltered,
+}
+
+impl PortState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PortState::Open => "open",
+            PortState::Closed => "closed",
+            PortState::Filtered => "filtered",
+            PortState::Unfiltered => "unfiltered",
+            PortState::OpenFiltered => "open|filtered",
+        }
+    }
+}
+
+/// The raw-packet scan techniques, named as in nmap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Technique {
+    Syn,
+    Ack,
+    Fin,
+    Null,
+    Xmas,
+    Window,
+}
+
+impl Technique {
+    pub fn name(self) -> &'static str {
+        match self {
+            Technique::Syn => "SYN",
+            Technique::Ack => "ACK",
+            Technique::Fin => "FIN",
+            Technique::Null => "NULL",
+            Technique::Xmas => "Xmas",
+            Technique::Window => "Window",
+        }
+    }
+
+    fn flags(self) -> u8 {
+        match self {
+            Technique::Syn => SYN,
+            Technique::Ack | Technique::Window => ACK,
+            Technique::Fin => FIN,
+            Technique::Null => 0,
+            Technique::Xmas => FIN | PSH | URG,
+        }
+    }
+
+    /// The state implied by a TCP reply, or `None` if the reply says nothing.
+    fn classify(self, reply: &TcpReply) -> Option<PortState> {
+        let rst = reply.flags & RST != 0;
+        match self {
+            Technique::Syn if reply.flags & (SYN | ACK) == SYN | ACK => Some(PortState::Open),
+            Technique::Syn | Technique::Fin | Technique::Null | Technique::Xmas if rst => {
+                Some(PortState::Closed)
+            }
+            Technique::Ack if rst => Some(PortState::Unfiltered),
+            // Some stacks set a non-zero window only in RSTs from listening ports.
+            Technique::Window if rst && reply.window > 0 => Some(PortState::Open),
+            Technique::Window if rst => Some(PortState::Closed),
+            _ => None,
+        }
+    }
+
+    /// The state of a port that never answered.
+    fn silent_state(self) -> PortState {
+        match self {
+            Technique::Fin | Technique::Null | Technique::Xmas => PortState::OpenFiltered,
+            _ => PortState::Filtered,
+        }
+    }
+}
+
+/// Whether this platform and process can run raw-packet scans.
+pub fn supported() -> bool {
+    cfg!(target_os = "linux") && raw::raw_sockets_available()
+}
+
+/// Sends one `technique` probe to every port, and again to ports that stay silent, and
+/// classifies the replies. At most `window` probes are sent per quarter of `reply_wait`;
+/// `on_sent` is called once per port. `keep_going` is asked before every batch and may
+/// block while the scan is paused; once it returns false nothing more is sent, and ports
+/// never probed are left out of the result.
+pub async fn scan(
+    target: Ipv4Addr,
+    ports: Vec<u16>,
+    technique: Technique,
+    window: usize,
+    reply_wait: Duration,
+    on_sent: impl Fn() + Send + 'static,
+    keep_going: impl Fn() -> bool + Send + 'static,
+) -> io::Result<BTreeMap<u16, PortState>> {
+    tokio::task::spawn_blocking(move || {
+        scan_blocking(
+            target, &ports, technique, window, reply_wait, on_sent, keep_going,
+        )
+    })
+    .await
+    .map_err(io::Error::other)?
+}
+
+fn scan_blocking(
+    target: Ipv4Addr,
+    ports: &[u16],
+    technique: Technique,
+    window: usize,
+    reply_wait: Duration,
+    on_sent: impl Fn(),
+    keep_going: impl Fn() -> bool,
+) -> io::Result<BTreeMap<u16, PortState>> {
+    let source = raw::source_addr_for(target)?;
+    let sender = RawSocket::tcp()?;
+    let receiver = RawSocket::tcp()?;
+    receiver.set_recv_buffer_size(RECEIVE_BUFFER_SIZE)?;
+    let icmp_receiver = RawSocket::icmp()?;
+    let src_port = source::port().unwrap_or_else(|| 40000 + (raw::random_u32() % 20000) as u16);
+    let seq = raw::random_u32();
+    let ack = if technique.flags() & ACK != 0 {
+        raw::random_u32()
+    } else {
+        0
+    };
+    let wanted: HashSet<u16> = ports.iter().copied().collect();
+
+    let results: Mutex<BTreeMap<u16, PortState>> = Mutex::new(BTreeMap::new());
+    // Set by the sender once the last probe is out; the receivers stop at this instant.
+    let finish: OnceLock<Instant> = OnceLock::new();
+    let record = |port: u16, state: PortState| {
+        let mut results = results.lock().unwrap();
+        results.entry(port).or_insert(state);
+        results.len() == wanted.len()
+    };
+
+    let probed = std::thread::scope(|scope| {
+        scope.spawn(|| {
+            receive_until_finished(&receiver, &finish, |packet| {
+                let Some(reply) = raw::parse_tcp(packet) else {
+                    return false;
+                };
+                if reply.ip.src != target
+                    || reply.dst_port != src_port
+                    || !wanted.contains(&reply.src_port)
+                {
+                    return false;
+                }
+                technique
+                    .classify(&reply)
+                    .is_some_and(|state| record(reply.src_port, state))
+            })
+        });
+        scope.spawn(|| {
+            receive_until_finished(&icmp_receiver, &finish, |packet| {
+                // Unreachables may come from any router on the path, so match on the
+                // quoted probe rather than the sender.
+                match raw::parse_icmp(packet).and_then(|r| r.filtered_tcp_probe()) {
+                    Some((dst, sport, dport))
+                        if dst == target && sport == src_port && wanted.contains(&dport) =>
+                    {
+                        record(dport, PortState::Filtered)
+                    }
+                    _ => false,
+                }
+            })
+        });
+
+        let segment_for = |dst_port| TcpSegment {
+            src: source,
+            dst: target,
+            src_port,
+            dst_port,
+            seq,
+            ack,
+            flags: technique.flags(),
+            window: 1024,
+            options: if technique == Technique::Syn {
+                &[raw::TcpOption::Mss(1460)]
+            } else {
+                &[]
+            },
+        };
+        let unanswered = || {
+            let results = results.lock().unwrap();
+            ports
+                .iter()
+                .copied()
+                .filter(|port| !results.contains_key(port))
+                .collect::<Vec<_>>()
+        };
+        let send = |ports: &[u16], on_sent: &dyn Fn()| {
+            send_probes(
+                &sender,
+                ports,
+                window,
+                reply_wait / 4,
+                on_sent,
+                &keep_going,
+                segment_for,
+            )
+        };
+        let send_result = send_rounds(ports, reply_wait, &on_sent, send, unanswered);
+        let _ = finish.set(Instant::now() + reply_wait);
+        send_result
+    })?;
+
+    let mut results = results.into_inner().unwrap();
+    for &port in &ports[..probed] {
+        results
+            .entry(port)
+            .or_insert_with(|| technique.silent_state());
+    }
+    Ok(results)
+}
+
+/// Feeds packets to `handle` until `finish` passes or `handle` reports it has seen
+/// everything it needs.
+fn receive_until_finished(
+    socket: &RawSocket,
+    finish: &OnceLock<Instant>,
+    mut handle: impl FnMut(&[u8]) -> bool,
+) {
+    loop {
+        let now = Instant::now();
+        let poll_until = match finish.get() {
+            Some(&end) if now >= end => break,
+            Some(&end) => end.min(now + RECEIVE_POLL),
+            None => now + RECEIVE_POLL,
+        };
+        if let Some(packet) = socket.recv_until(poll_until)
+            && handle(&packet)
+        {
+            break;
+        }
+    }
+}
+
+/// Waits up to `wait` for `done`, returning whether anything is still outstanding.
+fn wait_for_replies(wait: Duration, done: impl Fn() -> bool) -> bool {
+    let deadline = Instant::now() + wait;
+    while !done() {
+        let now = Instant::now();
+        if now >= deadline {
+            return true;
+        }
+        std::thread::sleep(RECEIVE_POLL.min(deadline - now));
+    }
+    false
+}
+
+/// Probes every port with `send`, then retransmits to the `unanswered` ones. Returns
+/// how many of `ports` were probed before the scan was stopped.
+fn send_rounds(
+    ports: &[u16],
+    reply_wait: Duration,
+    on_sent: &dyn Fn(),
+    send: impl Fn(&[u16], &dyn Fn()) -> io::Result<usize>,
+    unanswered: impl Fn() -> Vec<u16>,
+) -> io::Result<usize> {
+    let probed = send(ports, on_sent)?;
+    if probed < ports.len() {
+        return Ok(probed);
+    }
+    for _ in 0..RETRANSMISSIONS {
+        if !wait_for_replies(reply_wait, || unanswered().is_empty()) {
+            break;
+        }
+        let resend = unanswered();
+        if send(&resend, &|| {})? < resend.len() {
+            break;
+        }
+    }
+    Ok(probed)
+}
+
+/// Sends one segment per port, at most `window` of them per `batch_interval`, until
+/// `keep_going` says to stop. Returns how many ports were sent to.
+fn send_probes<'a>(
+    sender: &RawSocket,
+    ports: &[u16],
+    window: usize,
+    batch_interval: Duration,
+    on_sent: &dyn Fn(),
+    keep_going: &impl Fn() -> bool,
+    segment_for: impl Fn(u16) -> TcpSegment<'a>,
+) -> io::Result<usize> {
+    let mut sent = 0;
+    for batch in ports.chunks(window.max(1)) {
+        if !keep_going() {
+            break;
+        }
+        let started = Instant::now();
+        for &port in batch {
+            let segment = segment_for(port);
+            sender.send_to(&segment.encode(), segment.dst)?;
+            on_sent();
+        }
+        sent += batch.len();
+        if let Some(rest) = batch_interval.checked_sub(started.elapsed()) {
+            std::thread::sleep(rest);
+        }
+    }
+    Ok(sent)
+}
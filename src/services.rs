@@ -0,0 +1,167 @@
+use once_cell::sync::Lazy;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::OnceLock;
+
+/// The embedded port table, in nmap-services format.
+const EMBEDDED_SERVICES: &str = include_str!("../data/services.tsv");
+
+/// Entries loaded from `--services-file`, installed once before scanning.
+static OVERRIDES: OnceLock<HashMap<(u16, Protocol), ServiceEntry>> = OnceLock::new();
+
+static SERVICES: Lazy<HashMap<(u16, Protocol), ServiceEntry>> = Lazy::new(|| {
+    parse_services(EMBEDDED_SERVICES).expect("embedded services table is well formed")
+});
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl Protocol {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Protocol::Tcp => "tcp",
+            Protocol::Udp => "udp",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct ServiceEntry {
+    name: String,
+    /// Fraction of scanned hosts observed with this port open.
+    frequency: f64,
+}
+
+/// The conventional service name for `port`, or "unknown".
+pub fn service_name(port: u16, protocol: Protocol) -> String {
+    lookup(port, protocol).map_or_else(|| "unknown".to_string(), |entry| entry.name.clone())
+}
+
+/// `--services-file` entries win over the embedded table, whenever they were loaded.
+fn lookup(port: u16, protocol: Protocol) -> Option<&'static ServiceEntry> {
+    OVERRIDES
+        .get()
+        .and_then(|overrides| overrides.get(&(port, protocol)))
+        .or_else(|| SERVICES.get(&(port, protocol)))
+}
+
+/// Every known port for `protocol` with its open frequency, overrides applied.
+fn known_ports(protocol: Protocol) -> Vec<(u16, f64)> {
+    let ports: HashSet<u16> = SERVICES
+        .keys()
+        .chain(OVERRIDES.get().into_iter().flat_map(|o| o.keys()))
+        .filter(|(_, p)| *p == protocol)
+        .map(|(port, _)| *port)
+        .collect();
+    ports
+        .into_iter()
+        .map(|port| (port, lookup(port, protocol).map_or(0.0, |e| e.frequency)))
+        .collect()
+}
+
+/// The `count` ports most often found open for `protocol`, in ascending order.
+pub fn top_ports(count: usize, protocol: Protocol) -> Vec<u16> {
+    let mut ranked = known_ports(protocol);
+    ranked.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+    let mut ports: Vec<u16> = ranked
+        .into_iter()
+        .take(count)
+        .map(|(port, _)| port)
+        .collect();
+    ports.sort_unstable();
+    ports
+}
+
+/// Loads port assignments from `path` that take precedence over the embedded table.
+/// Must be called before the first lookup.
+pub fn load_services_file(path: &Path) -> Result<usize, String> {
+    let source = std::fs::read_to_string(path)
+        .map_err(|e| format!("{}: could not read file: {}", path.display(), e))?;
+    let overrides = parse_services(&source).map_err(|e| format!("{}:{}", path.display(), e))?;
+    let count = overrides.len();
+    OVERRIDES
+        .set(overrides)
+        .map_err(|_| "services were already loaded".to_string())?;
+    Ok(count)
+}
+
+fn parse_services(source: &str) -> Result<HashMap<(u16, Protocol), ServiceEntry>, String> {
+    let mut services = HashMap::new();
+    for (index, line) in source.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let error = |message: &str| format!("{}: {}: '{}'", index + 1, message, line);
+        let mut fields = line.split_whitespace();
+        let (Some(name), Some(port_proto)) = (fields.next(), fields.next()) else {
+            return Err(error("expected '<name> <port>/<protocol> [frequency]'"));
+        };
+        let (port, protocol) = port_proto
+            .split_once('/')
+            .ok_or_else(|| error("expected '<port>/<protocol>'"))?;
+        let port: u16 = port.parse().map_err(|_| error("invalid port"))?;
+        let protocol = match protocol {
+            "tcp" => Protocol::Tcp,
+            "udp" => Protocol::Udp,
+            // Other transports (sctp, ddp) are listed in some tables; skip them.
+            _ => continue,
+        };
+        let frequency = match fields.next() {
+            Some(frequency) => frequency.parse().map_err(|_| error("invalid frequency"))?,
+            None => 0.0,
+        };
+        services.insert(
+            (port, protocol),
+            ServiceEntry {
+                name: name.to_string(),
+                frequency,
+            },
+        );
+    }
+    Ok(services)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_nmap_services_lines() {
+        let services = parse_services(
+            "# comment\nhttp\t80/tcp\t0.484143\t# World Wide Web HTTP\nsnmp 161/udp\nsctp-only 9/sctp 0.1\n",
+        )
+        .unwrap();
+        assert_eq!(services.len(), 2);
+        let http = &services[&(80, Protocol::Tcp)];
+        assert_eq!((http.name.as_str(), http.frequency), ("http", 0.484143));
+        assert_eq!(services[&(161, Protocol::Udp)].frequency, 0.0);
+    }
+
+    #[test]
+    fn reports_the_offending_line() {
+        let error = parse_services("http 80/tcp\nbroken 99999/tcp\n").unwrap_err();
+        assert_eq!(error, "2: invalid port: 'broken 99999/tcp'");
+        let error = parse_services("http 80/tcp often\n").unwrap_err();
+        assert_eq!(error, "1: invalid frequency: 'http 80/tcp often'");
+        assert!(parse_services("http\n").is_err());
+    }
+
+    #[test]
+    fn top_ports_follow_open_frequency() {
+        assert_eq!(top_ports(3, Protocol::Tcp), [23, 80, 443]);
+        assert_eq!(top_ports(5, Protocol::Tcp), [21, 22, 23, 80, 443]);
+        assert!(top_ports(0, Protocol::Udp).is_empty());
+    }
+
+    #[test]
+    fn names_ports_by_protocol() {
+        assert_eq!(service_name(22, Protocol::Tcp), "ssh");
+        assert_eq!(service_name(161, Protocol::Udp), "snmp");
+        assert_eq!(service_name(161, Protocol::Tcp), "snmp");
+        assert_eq!(service_name(0, Protocol::Tcp), "unknown");
+    }
+}
@@ -0,0 +1,62 @@
+//! Graceful stop: once requested, no new probes are started, probes in flight finish, and
+//! the scan reports what it has so far.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::sync::Notify;
+
+#[derive(Default)]
+pub struct Shutdown {
+    requested: AtomicBool,
+    /// Whether Ctrl-C or SIGTERM asked for the stop, rather than the `q` key.
+    signalled: AtomicBool,
+    notify: Notify,
+}
+
+impl Shutdown {
+    /// Asks the scan to stop. Returns false if a stop was already requested.
+    pub fn request(&self) -> bool {
+        let first = !self.requested.swap(true, Ordering::SeqCst);
+        if first {
+            self.notify.notify_waiters();
+        }
+        first
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    /// Records that a signal is behind the stop, which decides the exit status.
+    pub fn note_signal(&self) {
+        self.signalled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn was_signalled(&self) -> bool {
+        self.signalled.load(Ordering::SeqCst)
+    }
+
+    /// Resolves once a stop has been requested.
+    pub async fn requested(&self) {
+        let notified = self.notify.notified();
+        if self.is_requested() {
+            return;
+        }
+        notified.await;
+    }
+}
+
+/// Waits for Ctrl-C, or SIGTERM on Unix.
+pub async fn interrupted() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        if let Ok(mut terminate) = signal(SignalKind::terminate()) {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = terminate.recv() => {}
+            }
+            return;
+        }
+    }
+    let _ = tokio::signal::ctrl_c().await;
+}
@@ -0,0 +1,165 @@
+//! Where the scan's packets come from: `--source-ip`, `--interface` and `--source-port`.
+//!
+//! Set once at startup and applied to every socket opened afterwards, so connects,
+//! service probes, UDP probes and raw packets all leave from the same place.
+
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::OnceLock;
+use tokio::net::{TcpSocket, TcpStream, UdpSocket};
+
+#[derive(Debug, Clone, Default)]
+pub struct Source {
+    pub ip: Option<IpAddr>,
+    /// Network interface to send through (SO_BINDTODEVICE), Linux only.
+    pub interface: Option<String>,
+    pub port: Option<u16>,
+}
+
+static SOURCE: OnceLock<Source> = OnceLock::new();
+
+/// Checks that sockets for `targets` can be bound as `source` asks, then makes every later
+/// socket use it. `udp` also checks a UDP bind, for UDP scans.
+pub fn configure(source: Source, targets: &[IpAddr], udp: bool) -> Result<(), String> {
+    if source.ip.is_none() && source.interface.is_none() && source.port.is_none() {
+        return Ok(());
+    }
+    let mut checked = Vec::new();
+    for &target in targets {
+        let family = Domain::for_address(SocketAddr::new(target, 0));
+        if checked.contains(&family) {
+            continue;
+        }
+        checked.push(family);
+        let target = SocketAddr::new(target, 0);
+        bound_socket(&source, target, Type::STREAM, Protocol::TCP).map_err(|e| e.to_string())?;
+        if udp {
+            bound_socket(&source, target, Type::DGRAM, Protocol::UDP).map_err(|e| e.to_string())?;
+        }
+    }
+    let _ = SOURCE.set(source);
+    Ok(())
+}
+
+pub fn ip() -> Option<IpAddr> {
+    SOURCE.get()?.ip
+}
+
+pub fn port() -> Option<u16> {
+    SOURCE.get()?.port
+}
+
+/// Connects to `addr` over TCP from the configured source.
+pub async fn connect_tcp(addr: SocketAddr) -> io::Result<TcpStream> {
+    let Some(source) = SOURCE.get() else {
+        return TcpStream::connect(addr).await;
+    };
+    let socket = bound_socket(source, addr, Type::STREAM, Protocol::TCP)?;
+    socket.set_nonblocking(true)?;
+    TcpSocket::from_std_stream(socket.into())
+        .connect(addr)
+        .await
+}
+
+/// A UDP socket for talking to `addr`, bound to the configured source or an ephemeral
+/// port of the right address family.
+pub async fn udp_socket(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let Some(source) = SOURCE.get() else {
+        let bind_addr: SocketAddr = match addr.ip() {
+            IpAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            IpAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        return UdpSocket::bind(bind_addr).await;
+    };
+    let socket = bound_socket(source, addr, Type::DGRAM, Protocol::UDP)?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// Binds a raw IPv4 socket to the configured address and interface. Raw packets carry
+/// their own ports, so `--source-port` is applied where they are built.
+pub fn bind_raw(socket: &Socket) -> io::Result<()> {
+    let Some(source) = SOURCE.get() else {
+        return Ok(());
+    };
+    bind_interface(socket, source)?;
+    if let Some(ip @ IpAddr::V4(_)) = source.ip {
+        bind(socket, SocketAddr::new(ip, 0))?;
+    }
+    Ok(())
+}
+
+/// Applies `--interface` to `socket`.
+fn bind_interface(socket: &Socket, source: &Source) -> io::Result<()> {
+    let Some(interface) = &source.interface else {
+        return Ok(());
+    };
+    #[cfg(target_os = "linux")]
+    return socket.bind_device(Some(interface.as_bytes())).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("cannot bind to interface {}: {}", interface, e),
+        )
+    });
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = socket;
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!(
+                "cannot bind to interface {}: only supported on Linux",
+                interface
+            ),
+        ))
+    }
+}
+
+/// Applies `--interface` to a socket used only to ask which address a route would use.
+pub fn bind_lookup(socket: &Socket) -> io::Result<()> {
+    match SOURCE.get() {
+        Some(source) => bind_interface(socket, source),
+        None => Ok(()),
+    }
+}
+
+fn bound_socket(
+    source: &Source,
+    target: SocketAddr,
+    kind: Type,
+    protocol: Protocol,
+) -> io::Result<Socket> {
+    if let Some(ip) = source.ip
+        && ip.is_ipv4() != target.is_ipv4()
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "source address {} cannot reach {}: the address families differ",
+                ip,
+                target.ip()
+            ),
+        ));
+    }
+    let socket = Socket::new(Domain::for_address(target), kind, Some(protocol))?;
+    bind_interface(&socket, source)?;
+    if source.ip.is_none() && source.port.is_none() {
+        return Ok(socket);
+    }
+    if source.port.is_some() {
+        // Every probe shares the port; each still has its own remote address and port.
+        socket.set_reuse_address(true)?;
+    }
+    let ip = source.ip.unwrap_or(match target.ip() {
+        IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        IpAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    });
+    bind(&socket, SocketAddr::new(ip, source.port.unwrap_or(0)))?;
+    Ok(socket)
+}
+
+fn bind(socket: &Socket, local: SocketAddr) -> io::Result<()> {
+    socket
+        .bind(&local.into())
+        .map_err(|e| io::Error::new(e.kind(), format!("cannot bind to {}: {}", local, e)))
+}
@@ -0,0 +1,160 @@
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// Refuse target specifications that would expand past this many addresses.
+const MAX_TARGETS: u128 = 1 << 20;
+
+#[derive(Debug, Clone)]
+pub struct Target {
+    pub ip: IpAddr,
+    /// The name the address was resolved from, if it was given as a hostname.
+    pub hostname: Option<String>,
+}
+
+/// Expands IP addresses, CIDR ranges (`10.0.0.0/24`, `2001:db8::/120`) and hostnames
+/// into individual targets, dropping duplicates but keeping the given order.
+pub async fn expand(specs: &[String]) -> Result<Vec<Target>, String> {
+    let mut targets = Vec::new();
+    let mut seen = HashSet::new();
+    for spec in specs {
+        for target in expand_one(spec.trim()).await? {
+            if seen.insert(target.ip) {
+                targets.push(target);
+            }
+        }
+    }
+    if targets.len() as u128 > MAX_TARGETS {
+        return Err(format!(
+            "{} targets requested; at most {} are supported per scan",
+            targets.len(),
+            MAX_TARGETS
+        ));
+    }
+    Ok(targets)
+}
+
+async fn expand_one(spec: &str) -> Result<Vec<Target>, String> {
+    if let Some((address, prefix)) = spec.split_once('/') {
+        let address: IpAddr = address
+            .parse()
+            .map_err(|_| format!("invalid network address in '{}'", spec))?;
+        let prefix: u32 = prefix
+            .parse()
+            .map_err(|_| format!("invalid prefix length in '{}'", spec))?;
+        return expand_cidr(address, prefix).map_err(|e| format!("'{}': {}", spec, e));
+    }
+    if let Ok(ip) = spec.parse::<IpAddr>() {
+        return Ok(vec![Target { ip, hostname: None }]);
+    }
+    let mut addresses = tokio::net::lookup_host((spec, 0))
+        .await
+        .map_err(|e| format!("could not resolve '{}': {}", spec, e))?;
+    // Like other scanners, scan the first address a name resolves to.
+    let address = addresses
+        .next()
+        .ok_or_else(|| format!("'{}' resolved to no addresses", spec))?;
+    Ok(vec![Target {
+        ip: address.ip(),
+        hostname: Some(spec.to_string()),
+    }])
+}
+
+fn expand_cidr(address: IpAddr, prefix: u32) -> Result<Vec<Target>, String> {
+    let (bits, value) = match address {
+        IpAddr::V4(v4) => (32, u128::from(u32::from(v4))),
+        IpAddr::V6(v6) => (128, u128::from(v6)),
+    };
+    if prefix > bits {
+        return Err(format!("prefix length must be at most {}", bits));
+    }
+    let host_bits = bits - prefix;
+    if host_bits >= 64 || 1u128 << host_bits > MAX_TARGETS {
+        return Err(format!(
+            "range is too large; at most {} addresses are supported",
+            MAX_TARGETS
+        ));
+    }
+    let size = 1u128 << host_bits;
+    let network = value & !(size - 1);
+    Ok((network..network + size)
+        .map(|value| Target {
+            ip: match address {
+                IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::from(value as u32)),
+                IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::from(value)),
+            },
+            hostname: None,
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ips(targets: &[Target]) -> Vec<String> {
+        targets.iter().map(|t| t.ip.to_string()).collect()
+    }
+
+    #[test]
+    fn expands_ipv4_networks_from_any_member() {
+        let targets = expand_cidr("192.168.1.77".parse().unwrap(), 30).unwrap();
+        assert_eq!(
+            ips(&targets),
+            [
+                "192.168.1.76",
+                "192.168.1.77",
+                "192.168.1.78",
+                "192.168.1.79"
+            ]
+        );
+        assert_eq!(
+            expand_cidr("10.0.0.0".parse().unwrap(), 24).unwrap().len(),
+            256
+        );
+        assert_eq!(
+            ips(&expand_cidr("10.0.0.9".parse().unwrap(), 32).unwrap()),
+            ["10.0.0.9"]
+        );
+    }
+
+    #[test]
+    fn expands_ipv6_networks() {
+        let targets = expand_cidr("2001:db8::1234".parse().unwrap(), 126).unwrap();
+        assert_eq!(
+            ips(&targets),
+            [
+                "2001:db8::1234",
+                "2001:db8::1235",
+                "2001:db8::1236",
+                "2001:db8::1237"
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_bad_and_oversized_prefixes() {
+        assert!(expand_cidr("10.0.0.0".parse().unwrap(), 33).is_err());
+        assert!(expand_cidr("10.0.0.0".parse().unwrap(), 8).is_err());
+        assert_eq!(
+            expand_cidr("10.0.0.0".parse().unwrap(), 12).unwrap().len(),
+            MAX_TARGETS as usize
+        );
+        assert!(expand_cidr("2001:db8::".parse().unwrap(), 64).is_err());
+    }
+
+    #[tokio::test]
+    async fn expands_specs_in_order_without_duplicates() {
+        let specs = ["10.0.0.2/31", " 10.0.0.3 ", "::1", "10.0.0.1"].map(String::from);
+        let targets = expand(&specs).await.unwrap();
+        assert_eq!(ips(&targets), ["10.0.0.2", "10.0.0.3", "::1", "10.0.0.1"]);
+        assert!(targets.iter().all(|t| t.hostname.is_none()));
+    }
+
+    #[tokio::test]
+    async fn reports_malformed_ranges() {
+        let error = expand(&["10.0.0.0/abc".to_string()]).await.unwrap_err();
+        assert_eq!(error, "invalid prefix length in '10.0.0.0/abc'");
+        let error = expand(&["10.0.0.0/40".to_string()]).await.unwrap_err();
+        assert_eq!(error, "'10.0.0.0/40': prefix length must be at most 32");
+    }
+}